@@ -0,0 +1,65 @@
+//! Measures conversion throughput on a synthetic wide cohort.
+//!
+//! ```sh
+//! cargo run --release --no-default-features --example wide_cohort -- [samples] [records]
+//! ```
+
+use std::env;
+use std::fs::File;
+use std::io::{ BufWriter, Write };
+use std::time::Instant;
+
+use vcf2csv::{ Converter, Input, Output };
+
+fn write_cohort(path: &std::path::Path, samples: usize, records: usize) -> anyhow::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "##fileformat=VCFv4.2")?;
+    writeln!(out, "##contig=<ID=1,length={}>", records * 10 + 100)?;
+    writeln!(out, "##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Total Depth\">")?;
+    writeln!(out, "##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele Frequency\">")?;
+    writeln!(out, "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">")?;
+    writeln!(out, "##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"Read Depth\">")?;
+    writeln!(out, "##FORMAT=<ID=GQ,Number=1,Type=Integer,Description=\"Genotype Quality\">")?;
+    writeln!(out, "##FORMAT=<ID=AD,Number=R,Type=Integer,Description=\"Allelic depths\">")?;
+    write!(out, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT")?;
+    for s in 0..samples {
+        write!(out, "\tS{}", s)?;
+    }
+    writeln!(out)?;
+
+    for r in 0..records {
+        write!(out, "1\t{}\t.\tA\tG,T\t50\tPASS\tDP=100;AF=0.1,0.2\tGT:DP:GQ:AD", 100 + r * 10)?;
+        for s in 0..samples {
+            let (a1, a2) = ((r + s) % 3, (r * 7 + s) % 3);
+            write!(out, "\t{}/{}:{}:{}:3,4,5", a1, a2, 1 + (s % 60), 1 + ((r + s) % 99))?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = env::args().skip(1);
+    let samples = args.next().map_or(Ok(2000), |a| a.parse())?;
+    let records = args.next().map_or(Ok(500), |a| a.parse())?;
+
+    let dir = env::temp_dir();
+    let vcf_path = dir.join("vcf2csv_wide_cohort.vcf");
+    let csv_path = dir.join("vcf2csv_wide_cohort.tsv");
+    write_cohort(&vcf_path, samples, records)?;
+
+    let start = Instant::now();
+    Converter::new(Input::path(&vcf_path), Output::path(&csv_path)).run()?;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!(
+        "{} samples x {} records: {:.2}s, {:.0} records/s, {:.0} genotypes/s",
+        samples,
+        records,
+        elapsed,
+        records as f64 / elapsed,
+        (samples * records) as f64 / elapsed
+    );
+    Ok(())
+}
@@ -0,0 +1,328 @@
+use rust_htslib::bcf::header::{ HeaderView, TagType };
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::plan::{ Column, ColumnKind, HeaderDefinitions, HeaderPlan, TagSelection };
+use crate::sink::RowSink;
+use crate::writer::{ Layout, Writer };
+use crate::ParseError;
+
+/// What the name column of an interval holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BedName {
+    /// `CHROM-POS-REF-ALT`, unique to each ALT allele.
+    #[default]
+    Variant,
+    /// The record ID, or `CHROM-POS-REF-ALT` if it has none.
+    Id,
+}
+
+impl FromStr for BedName {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "variant" => Ok(BedName::Variant),
+            "id" => Ok(BedName::Id),
+            _ => Err(ParseError::UnknownOption { option: "bed_name", value: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for BedName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BedName::Variant => "variant",
+            BedName::Id => "id",
+        })
+    }
+}
+
+/// The plan of intervals: the site fields, the record ID, and the END of structural variants
+/// if the header declares it.
+pub(crate) fn plan(header: &HeaderView) -> anyhow::Result<HeaderPlan> {
+    let definitions = HeaderDefinitions::new(header);
+    let end = definitions.info.iter().find(|tag| tag.id == "END").map(|tag| tag.id.as_str());
+    let mut plan =
+        HeaderPlan::new(header, &TagSelection::only(end), &TagSelection::Only(Vec::new()))?;
+    plan.info.retain(|tag| tag.tag_type == TagType::Integer && !tag.is_multi_valued());
+    plan.id = true;
+    Ok(plan)
+}
+
+/// What a value of the planned row is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Chrom,
+    Pos,
+    Id,
+    Ref,
+    Alt,
+    Qual,
+    End,
+    Unused,
+}
+
+/// Writes rows as intervals for bedtools, one per ALT allele.
+///
+/// In BED, each line is the 0-based, half-open interval the REF allele covers, or up to END for
+/// a structural variant, and its name. In BEDPE, each line pairs the two ends of a variant: the
+/// mate a breakend ALT such as `N[chr2:3000[` joins, with the strands of the join, or the END of
+/// a structural variant. The second of two mated breakend records is left out, and ends of other
+/// variants are `.` and -1.
+pub struct BedWriter<W: Write> {
+    writer: Writer<W>,
+    name: BedName,
+    pairs: bool,
+    fields: Vec<Field>,
+    /// Index of the next value in the current row.
+    column: usize,
+    chrom: Vec<u8>,
+    pos: i64,
+    id: Vec<u8>,
+    reference: Vec<u8>,
+    alt: Vec<u8>,
+    qual: Option<f32>,
+    end: Option<i64>,
+    /// Breakend joins written so far, as their two contigs and positions.
+    joins: HashSet<(Vec<u8>, i64, Vec<u8>, i64)>,
+    /// Rows left out as the second record of a breakend pair.
+    dropped: u64,
+}
+
+impl<W: Write> BedWriter<W> {
+    /// Write BED intervals, or BEDPE pairs with `pairs`, named after `name`.
+    pub fn new(inner: W, name: BedName, pairs: bool) -> Self {
+        BedWriter {
+            writer: Writer::new(inner, Layout::Flat),
+            name,
+            pairs,
+            fields: Vec::new(),
+            column: 0,
+            chrom: Vec::new(),
+            pos: 0,
+            id: Vec::new(),
+            reference: Vec::new(),
+            alt: Vec::new(),
+            qual: None,
+            end: None,
+            joins: HashSet::new(),
+            dropped: 0,
+        }
+    }
+
+    fn next_field(&mut self) -> Field {
+        let field = self.fields.get(self.column).copied().unwrap_or(Field::Unused);
+        self.column += 1;
+        field
+    }
+
+    fn name(&self) -> Vec<u8> {
+        if self.name == BedName::Id && !self.id.is_empty() {
+            return self.id.clone();
+        }
+        let pos = self.pos.to_string();
+        [&self.chrom[..], pos.as_bytes(), &self.reference, &self.alt].join(&b'-')
+    }
+
+    /// The 0-based, half-open interval of the variant.
+    fn interval(&self) -> (i64, i64) {
+        let start = self.pos - 1;
+        let end = self.end.filter(|&end| end > start);
+        (start, end.unwrap_or(start + self.reference.len() as i64))
+    }
+
+    fn write_pair(&mut self) -> anyhow::Result<()> {
+        let (mate, strands) = match breakend(&self.alt) {
+            Some((contig, pos, strands)) => {
+                let join = (self.chrom.clone(), self.pos, contig.to_vec(), pos);
+                // The record of the mate points back at this one
+                if self.joins.remove(&(join.2.clone(), join.3, join.0.clone(), join.1)) {
+                    self.dropped += 1;
+                    return Ok(());
+                }
+                self.joins.insert(join);
+                (Some((contig.to_vec(), pos)), strands)
+            }
+            None => {
+                let end = self.end.filter(|&end| end >= self.pos);
+                (end.map(|end| (self.chrom.clone(), end)), (b'.', b'.'))
+            }
+        };
+
+        match mate {
+            Some((contig, pos)) => {
+                write_interval(&mut self.writer, &self.chrom, self.pos - 1, self.pos)?;
+                write_interval(&mut self.writer, &contig, pos - 1, pos)?;
+            }
+            None => {
+                let (start, end) = self.interval();
+                write_interval(&mut self.writer, &self.chrom, start, end)?;
+                write_interval(&mut self.writer, b".", -1, -1)?;
+            }
+        }
+        let name = self.name();
+        self.writer.write_field(&name)?;
+        match self.qual {
+            Some(qual) => self.writer.write_float(qual)?,
+            None => self.writer.write_field(b".")?,
+        }
+        self.writer.write_field(&[strands.0])?;
+        self.writer.write_field(&[strands.1])?;
+        self.writer.newline()
+    }
+}
+
+impl<W: Write> RowSink for BedWriter<W> {
+    fn metadata(&mut self, entries: &[(String, String)]) -> anyhow::Result<()> {
+        self.writer.metadata(entries)
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        self.fields = columns
+            .iter()
+            .map(|column| {
+                match (&column.kind, column.name.as_str()) {
+                    (ColumnKind::Fixed, "CHROM") => Field::Chrom,
+                    (ColumnKind::Fixed, "POS") => Field::Pos,
+                    (ColumnKind::Fixed, "ID") => Field::Id,
+                    (ColumnKind::Fixed, "REF") => Field::Ref,
+                    (ColumnKind::Fixed, "ALT") => Field::Alt,
+                    (ColumnKind::Fixed, "QUAL") => Field::Qual,
+                    (ColumnKind::Info, "END") => Field::End,
+                    _ => Field::Unused,
+                }
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.column = 0;
+        self.id.clear();
+        self.qual = None;
+        self.end = None;
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        match self.next_field() {
+            Field::Pos => self.pos = value.into(),
+            Field::End => self.end = Some(value.into()),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        if self.next_field() == Field::Qual {
+            self.qual = Some(value);
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        let target = match self.next_field() {
+            Field::Chrom => &mut self.chrom,
+            Field::Id => &mut self.id,
+            Field::Ref => &mut self.reference,
+            Field::Alt => &mut self.alt,
+            _ => return Ok(()),
+        };
+        target.clear();
+        target.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        self.next_field();
+        Ok(())
+    }
+
+    fn write_flag(&mut self, _value: bool) -> anyhow::Result<()> {
+        self.next_field();
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        if self.pairs {
+            return self.write_pair();
+        }
+        let (start, end) = self.interval();
+        write_interval(&mut self.writer, &self.chrom, start, end)?;
+        let name = self.name();
+        self.writer.write_field(&name)?;
+        self.writer.newline()
+    }
+
+    fn rows_dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.finish()
+    }
+}
+
+fn write_interval<W: Write>(
+    writer: &mut Writer<W>,
+    chrom: &[u8],
+    start: i64,
+    end: i64,
+) -> anyhow::Result<()> {
+    writer.write_field(chrom)?;
+    writer.write_field(start.to_string().as_bytes())?;
+    writer.write_field(end.to_string().as_bytes())
+}
+
+/// The mate of a breakend ALT, `t[p[`, `t]p]`, `]p]t` or `[p[t`: its contig, its 1-based
+/// position, and the strands of the two ends of the join.
+fn breakend(alt: &[u8]) -> Option<(&[u8], i64, (u8, u8))> {
+    let open = alt.iter().position(|&b| b == b'[' || b == b']')?;
+    let bracket = alt[open];
+    let close = open + 1 + alt[open + 1..].iter().position(|&b| b == bracket)?;
+    let mate = &alt[open + 1..close];
+    // Contig names may hold colons, positions may not
+    let colon = mate.iter().rposition(|&b| b == b':')?;
+    let pos = std::str::from_utf8(&mate[colon + 1..]).ok()?.parse().ok()?;
+    let contig = &mate[..colon];
+    let contig = contig.strip_prefix(b"<").and_then(|c| c.strip_suffix(b">")).unwrap_or(contig);
+
+    // The sequence before the brackets extends to the left of the join, and `[` points
+    // to the right of the mate
+    let first = if open > 0 { b'+' } else { b'-' };
+    let second = if bracket == b'[' { b'-' } else { b'+' };
+    Some((contig, pos, (first, second)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_mate_and_strands_of_each_breakend_form() {
+        // After G, the sequence right of the mate, or the reverse complement of that left of it
+        assert_eq!(breakend(b"G[2:3000["), Some((&b"2"[..], 3000, (b'+', b'-'))));
+        assert_eq!(breakend(b"G]2:3000]"), Some((&b"2"[..], 3000, (b'+', b'+'))));
+        // Before G, the sequence left of the mate, or the reverse complement of that right of it
+        assert_eq!(breakend(b"]2:3000]G"), Some((&b"2"[..], 3000, (b'-', b'+'))));
+        assert_eq!(breakend(b"[2:3000[G"), Some((&b"2"[..], 3000, (b'-', b'-'))));
+    }
+
+    #[test]
+    fn reads_contig_names_with_colons_or_angle_brackets() {
+        assert_eq!(breakend(b"A[HLA-A*01:01:5["), Some((&b"HLA-A*01:01"[..], 5, (b'+', b'-'))));
+        assert_eq!(breakend(b"]<ctg1>:17]A"), Some((&b"ctg1"[..], 17, (b'-', b'+'))));
+    }
+
+    #[test]
+    fn rejects_alleles_that_are_not_breakends() {
+        assert_eq!(breakend(b"C"), None);
+        assert_eq!(breakend(b"<DEL>"), None);
+        assert_eq!(breakend(b"G[2:3000"), None);
+        assert_eq!(breakend(b"G[2["), None);
+        assert_eq!(breakend(b"G[2:x["), None);
+    }
+}
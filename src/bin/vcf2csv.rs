@@ -0,0 +1,167 @@
+//! Command-line interface: converts a VCF/BCF file as `vcf2csv.to_txt` does, drawing a progress
+//! bar on a terminal.
+//!
+//! ```sh
+//! vcf2csv calls.vcf.gz calls.tsv --info DP,AF --layout flat
+//! ```
+
+use std::env;
+use std::io::{ self, IsTerminal, Write };
+use std::process::ExitCode;
+
+use vcf2csv::{ ConversionOptions, Converter, Input, Output, Progress, TagSelection };
+
+const USAGE: &str = "\
+usage: vcf2csv [options] <input> <output>
+
+Convert a VCF/BCF file into a table. The input or output may be `-` for stdin or stdout.
+
+options:
+  --format <format>          tsv, jsonl, sqlite, xlsx, pgcopy, pgcopy-binary, matrix, npy,
+                             plink, maf, bed, bedpe or somatic [tsv]
+  --layout <layout>          grouped or flat [grouped]
+  --info <tags>              comma-separated INFO tags to write [all]
+  --format-tags <tags>       comma-separated FORMAT tags to write [all]
+  --on-error <policy>        fail or skip [fail]
+  --threads <n>              worker threads [1]
+  --by-contig                convert an indexed input one contig per thread
+  --genotype-table           write FORMAT values to a long genotypes table
+  --ref-alt-columns          split Number=R tags into _REF and _ALT columns, with VAF
+  --sheet-per-contig         start a new XLSX worksheet for each contig
+  --matrix-value <value>     dosage or a FORMAT tag, for matrix output [dosage]
+  --non-diploid <policy>     homozygous, missing or fail, for PLINK output [homozygous]
+  --multiallelic <policy>    split or missing, for PLINK output [split]
+  --tumor-sample <name>      tumor sample of MAF and somatic output
+  --normal-sample <name>     matched normal sample of MAF and somatic output
+  --bed-name <name>          variant or id, the name column of BED output [variant]
+  --metadata                 keep the VCF meta-information lines as comments
+  --provenance               record the version, options and input size
+  --checksum                 also record the SHA-256 of the input
+  --schema <path>            also write the data dictionary of the output
+  --create-table <path>      also write the CREATE TABLE of pgcopy output
+  --quiet                    draw no progress bar
+  --help                     print this help";
+
+/// Records between two redraws of the progress bar.
+const PROGRESS_EVERY: u64 = 10_000;
+/// Characters of the bar itself.
+const BAR_WIDTH: usize = 30;
+
+/// The parsed command line.
+struct Args {
+    input: Input,
+    output: Output,
+    options: ConversionOptions,
+    quiet: bool,
+}
+
+fn main() -> ExitCode {
+    let args = match parse(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("vcf2csv: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let bar = !args.quiet && io::stderr().is_terminal();
+    let mut converter = Converter::new(args.input, args.output).with_options(args.options);
+    if bar {
+        converter = converter.with_progress(PROGRESS_EVERY, |progress| {
+            let mut stderr = io::stderr().lock();
+            stderr.write_all(render(progress).as_bytes())?;
+            stderr.flush()?;
+            Ok(())
+        });
+    }
+    let result = converter.run();
+    if bar {
+        eprintln!();
+    }
+    match result {
+        Ok(report) => {
+            if bar {
+                eprintln!(
+                    "{} records into {} rows in {:.1}s",
+                    report.records_read,
+                    report.rows_written,
+                    report.elapsed.as_secs_f64()
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("vcf2csv: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The arguments of the command line, or `None` if it asks for help.
+fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Option<Args>> {
+    let mut options = ConversionOptions::new();
+    let mut quiet = false;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--format" => options = options.format(value()?.parse()?),
+            "--layout" => options = options.layout(value()?.parse()?),
+            "--info" => options = options.info_tags(tags(&value()?)),
+            "--format-tags" => options = options.format_tags(tags(&value()?)),
+            "--on-error" => options = options.on_error(value()?.parse()?),
+            "--threads" => options = options.threads(value()?.parse()?),
+            "--by-contig" => options = options.by_contig(true),
+            "--genotype-table" => options = options.genotype_table(true),
+            "--ref-alt-columns" => options = options.ref_alt_columns(true),
+            "--sheet-per-contig" => options = options.sheet_per_contig(true),
+            "--matrix-value" => options = options.matrix_value(value()?.parse()?),
+            "--non-diploid" => options = options.non_diploid(value()?.parse()?),
+            "--multiallelic" => options = options.multiallelic(value()?.parse()?),
+            "--tumor-sample" => options = options.tumor_sample(value()?),
+            "--normal-sample" => options = options.normal_sample(value()?),
+            "--bed-name" => options = options.bed_name(value()?.parse()?),
+            "--metadata" => options = options.metadata(true),
+            "--provenance" => options = options.provenance(true),
+            "--checksum" => options = options.checksum(true),
+            "--schema" => options = options.schema(value()?),
+            "--create-table" => options = options.create_table(value()?),
+            "--quiet" => quiet = true,
+            _ if arg.starts_with("--") => anyhow::bail!("unknown option {}", arg),
+            _ => paths.push(arg),
+        }
+    }
+    let [input, output]: [String; 2] = paths
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected an input and an output path"))?;
+    let input = if input == "-" { Input::Stdin } else { Input::path(input) };
+    let output = if output == "-" { Output::stdout() } else { Output::path(output) };
+    Ok(Some(Args { input, output, options, quiet }))
+}
+
+fn tags(list: &str) -> TagSelection {
+    TagSelection::only(list.split(',').filter(|tag| !tag.is_empty()))
+}
+
+/// The progress line, redrawn over the previous one: a bar when the fraction converted is
+/// known, then the last position read and the records read so far.
+fn render(progress: &Progress) -> String {
+    let mut line = String::from("\r");
+    if let Some(fraction) = progress.fraction {
+        let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+        let bar = format!("{:<width$}", "=".repeat(filled.min(BAR_WIDTH)), width = BAR_WIDTH);
+        line.push_str(&format!("[{}] {:>3.0}%  ", bar, fraction * 100.0));
+    }
+    if !progress.contig.is_empty() {
+        line.push_str(&format!("{}:{}  ", progress.contig, progress.pos));
+    }
+    // Clear what is left of a longer previous line
+    line.push_str(&format!("{} records\x1b[K", progress.records));
+    line
+}
@@ -0,0 +1,894 @@
+use rust_htslib::{ bcf, bcf::Read };
+use rust_htslib::bcf::header::HeaderView;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::bail;
+
+use crate::bed::{ self, BedName, BedWriter };
+use crate::format::RowFormatter;
+use crate::json_lines::JsonLinesWriter;
+use crate::normalized::{ self, NormalizedWriter };
+use crate::parallel;
+use crate::plan::{ self, HeaderDefinitions, HeaderPlan, TagSelection };
+use crate::progress::{ Checkpoint, Progress };
+use crate::provenance::Provenance;
+use crate::report::{ ConversionReport, Tally };
+use crate::maf::{ self, MafWriter };
+use crate::matrix::{ self, MatrixValue, MatrixWriter };
+use crate::plink::{ self, Multiallelic, NonDiploid, PlinkWriter };
+use crate::postgres::{ self, PostgresWriter };
+use crate::schema;
+use crate::sink::RowSink;
+use crate::somatic::{ self, SomaticWriter };
+use crate::source::Source;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::SqliteWriter;
+#[cfg(feature = "xlsx")]
+use crate::xlsx::XlsxWriter;
+use crate::writer::{ Layout, Writer };
+use crate::ParseError;
+
+/// What to do with records htslib fails to read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Abort the conversion on the first unreadable record.
+    #[default]
+    Fail,
+    /// Leave unreadable records out of the output and carry on, unless a thousand in a row
+    /// are, as past the end of a truncated file.
+    Skip,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ErrorPolicy::Fail),
+            "skip" => Ok(ErrorPolicy::Skip),
+            _ => Err(ParseError::UnknownOption { option: "on_error", value: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ErrorPolicy::Fail => "fail",
+            ErrorPolicy::Skip => "skip",
+        })
+    }
+}
+
+/// The file format of a byte output; a `Output::Sink` decides its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A tab-separated table, with the header rows of its `Layout`.
+    #[default]
+    Tsv,
+    /// One JSON object per row, with nested `info` and `samples` objects.
+    JsonLines,
+    /// A SQLite database, only written to a path.
+    Sqlite,
+    /// An Excel workbook, with typed cells and a frozen, filterable header.
+    Xlsx,
+    /// The text format of PostgreSQL `COPY ... FROM`.
+    PostgresText,
+    /// The binary format of PostgreSQL `COPY ... FROM ... (FORMAT binary)`.
+    PostgresBinary,
+    /// A tab-separated matrix of one value per ALT allele and sample, with a row-key file.
+    Matrix,
+    /// The matrix as a NumPy `.npy` array, with row-key and sample name files.
+    Npy,
+    /// A PLINK 1.9 binary fileset of `.bed`, `.bim` and `.fam` files.
+    Plink,
+    /// A Mutation Annotation Format table of the calls of a tumor sample.
+    Maf,
+    /// BED intervals of the variants, named after them.
+    Bed,
+    /// BEDPE pairs of the two ends of each structural variant.
+    Bedpe,
+    /// The calls of a tumor sample beside its matched normal, with depths and allele
+    /// frequencies.
+    Somatic,
+}
+
+impl OutputFormat {
+    fn is_matrix(self) -> bool {
+        matches!(self, OutputFormat::Matrix | OutputFormat::Npy)
+    }
+
+    fn is_copy(self) -> bool {
+        matches!(self, OutputFormat::PostgresText | OutputFormat::PostgresBinary)
+    }
+
+    /// Whether the format writes columns of its own rather than those of the tag selections.
+    fn chooses_columns(self) -> bool {
+        self.is_matrix()
+            || matches!(
+                self,
+                OutputFormat::Plink
+                    | OutputFormat::Maf
+                    | OutputFormat::Bed
+                    | OutputFormat::Bedpe
+                    | OutputFormat::Somatic
+            )
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tsv" => Ok(OutputFormat::Tsv),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "sqlite" => Ok(OutputFormat::Sqlite),
+            "xlsx" => Ok(OutputFormat::Xlsx),
+            "pgcopy" => Ok(OutputFormat::PostgresText),
+            "pgcopy-binary" => Ok(OutputFormat::PostgresBinary),
+            "matrix" => Ok(OutputFormat::Matrix),
+            "npy" => Ok(OutputFormat::Npy),
+            "plink" => Ok(OutputFormat::Plink),
+            "maf" => Ok(OutputFormat::Maf),
+            "bed" => Ok(OutputFormat::Bed),
+            "bedpe" => Ok(OutputFormat::Bedpe),
+            "somatic" => Ok(OutputFormat::Somatic),
+            _ => Err(ParseError::UnknownOption { option: "format", value: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::JsonLines => "jsonl",
+            OutputFormat::Sqlite => "sqlite",
+            OutputFormat::Xlsx => "xlsx",
+            OutputFormat::PostgresText => "pgcopy",
+            OutputFormat::PostgresBinary => "pgcopy-binary",
+            OutputFormat::Matrix => "matrix",
+            OutputFormat::Npy => "npy",
+            OutputFormat::Plink => "plink",
+            OutputFormat::Maf => "maf",
+            OutputFormat::Bed => "bed",
+            OutputFormat::Bedpe => "bedpe",
+            OutputFormat::Somatic => "somatic",
+        })
+    }
+}
+
+/// Everything about a conversion that is not its input or output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionOptions {
+    pub format: OutputFormat,
+    pub layout: Layout,
+    pub info_tags: TagSelection,
+    pub format_tags: TagSelection,
+    pub on_error: ErrorPolicy,
+    /// Worker threads; 1 converts on the calling thread. A single TSV table written as text has
+    /// its rows formatted on them; every other output, including linked tables and custom
+    /// sinks, only has the input decompressed on them.
+    pub threads: usize,
+    /// Convert an indexed input one contig per worker, each with its own reader. Rows come out
+    /// in header contig order rather than file order. A header without contig lines is read
+    /// through sequentially instead. Only for TSV output.
+    pub by_contig: bool,
+    /// Write FORMAT values to a long `genotypes` table, one row per sample, instead of as
+    /// columns of each variant. For TSV, the output path is then a directory of linked
+    /// `variants`, `alleles`, `samples` and `genotypes` tables.
+    pub genotype_table: bool,
+    /// Write each Number=R tag as `_REF` and `_ALT` columns, and a VAF column derived from AD
+    /// for each sample. Not for the formats that choose their own columns, e.g. MAF or PLINK.
+    pub ref_alt_columns: bool,
+    /// Start a new worksheet for each contig. Only for XLSX output.
+    pub sheet_per_contig: bool,
+    /// What the cells of a matrix hold. Only for matrix output, which ignores the tag
+    /// selections.
+    pub matrix_value: MatrixValue,
+    /// How PLINK output codes calls that are not diploid.
+    pub non_diploid: NonDiploid,
+    /// How PLINK output codes the calls of multi-allelic sites.
+    pub multiallelic: Multiallelic,
+    /// The tumor sample of somatic output, by default the one of a `##tumor_sample` header
+    /// line, or the only sample.
+    pub tumor_sample: Option<String>,
+    /// The matched normal sample of somatic output, by default the one of a `##normal_sample`
+    /// header line, if any.
+    pub normal_sample: Option<String>,
+    /// What names the intervals of BED and BEDPE output.
+    pub bed_name: BedName,
+    /// Write the meta-information lines of the VCF header before the table.
+    pub metadata: bool,
+    /// Record how the table was produced, i.e. the version, options and input size, in the
+    /// report and as comment lines before the table.
+    pub provenance: bool,
+    /// Add the SHA-256 of the input to the provenance, which reads a file input once more
+    /// before converting it.
+    pub checksum: bool,
+    /// Where to write the data dictionary of the output, a JSON description of its columns. Only
+    /// for a single TSV, JSON Lines or COPY table.
+    pub schema: Option<PathBuf>,
+    /// Where to write a PostgreSQL `CREATE TABLE` statement for loading the COPY output, with
+    /// column types from the header. Only for COPY output.
+    pub create_table: Option<PathBuf>,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        ConversionOptions {
+            format: OutputFormat::default(),
+            layout: Layout::default(),
+            info_tags: TagSelection::default(),
+            format_tags: TagSelection::default(),
+            on_error: ErrorPolicy::default(),
+            threads: 1,
+            by_contig: false,
+            genotype_table: false,
+            ref_alt_columns: false,
+            sheet_per_contig: false,
+            matrix_value: MatrixValue::default(),
+            non_diploid: NonDiploid::default(),
+            multiallelic: Multiallelic::default(),
+            tumor_sample: None,
+            normal_sample: None,
+            bed_name: BedName::default(),
+            metadata: false,
+            provenance: false,
+            checksum: false,
+            schema: None,
+            create_table: None,
+        }
+    }
+}
+
+impl ConversionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn info_tags(mut self, selection: TagSelection) -> Self {
+        self.info_tags = selection;
+        self
+    }
+
+    pub fn format_tags(mut self, selection: TagSelection) -> Self {
+        self.format_tags = selection;
+        self
+    }
+
+    pub fn on_error(mut self, policy: ErrorPolicy) -> Self {
+        self.on_error = policy;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn by_contig(mut self, by_contig: bool) -> Self {
+        self.by_contig = by_contig;
+        self
+    }
+
+    pub fn genotype_table(mut self, genotype_table: bool) -> Self {
+        self.genotype_table = genotype_table;
+        self
+    }
+
+    pub fn ref_alt_columns(mut self, ref_alt_columns: bool) -> Self {
+        self.ref_alt_columns = ref_alt_columns;
+        self
+    }
+
+    pub fn sheet_per_contig(mut self, sheet_per_contig: bool) -> Self {
+        self.sheet_per_contig = sheet_per_contig;
+        self
+    }
+
+    pub fn matrix_value(mut self, value: MatrixValue) -> Self {
+        self.matrix_value = value;
+        self
+    }
+
+    pub fn non_diploid(mut self, policy: NonDiploid) -> Self {
+        self.non_diploid = policy;
+        self
+    }
+
+    pub fn multiallelic(mut self, policy: Multiallelic) -> Self {
+        self.multiallelic = policy;
+        self
+    }
+
+    pub fn tumor_sample<S: Into<String>>(mut self, sample: S) -> Self {
+        self.tumor_sample = Some(sample.into());
+        self
+    }
+
+    pub fn normal_sample<S: Into<String>>(mut self, sample: S) -> Self {
+        self.normal_sample = Some(sample.into());
+        self
+    }
+
+    pub fn bed_name(mut self, name: BedName) -> Self {
+        self.bed_name = name;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: bool) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn provenance(mut self, provenance: bool) -> Self {
+        self.provenance = provenance;
+        self
+    }
+
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn schema<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.schema = Some(path.into());
+        self
+    }
+
+    pub fn create_table<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.create_table = Some(path.into());
+        self
+    }
+}
+
+/// The VCF/BCF data to convert.
+pub enum Input {
+    /// A file, the only input `by_contig` can split.
+    Path(PathBuf),
+    /// The standard input of the process.
+    Stdin,
+    /// Any byte source, e.g. an in-memory buffer or a decompressing reader. htslib reads it
+    /// through a pipe, fed from the source by a thread of its own.
+    Reader(Box<dyn io::Read + Send>),
+}
+
+impl Input {
+    pub fn path<P: Into<PathBuf>>(path: P) -> Self {
+        Input::Path(path.into())
+    }
+
+    pub fn reader<R: io::Read + Send + 'static>(reader: R) -> Self {
+        Input::Reader(Box::new(reader))
+    }
+}
+
+/// Where the converted table goes.
+pub enum Output {
+    /// A file, created or truncated.
+    Path(PathBuf),
+    /// Any byte sink, e.g. an in-memory buffer.
+    Writer(Box<dyn Write + Send>),
+    /// A custom backend, receiving typed values instead of delimited text.
+    Sink(Box<dyn RowSink + Send>),
+}
+
+impl Output {
+    pub fn path<P: Into<PathBuf>>(path: P) -> Self {
+        Output::Path(path.into())
+    }
+
+    pub fn writer<W: Write + Send + 'static>(writer: W) -> Self {
+        Output::Writer(Box::new(writer))
+    }
+
+    pub fn stdout() -> Self {
+        Output::writer(io::stdout())
+    }
+
+    pub fn sink<S: RowSink + Send + 'static>(sink: S) -> Self {
+        Output::Sink(Box::new(sink))
+    }
+}
+
+/// Converts a VCF/BCF file into a delimited text table, one row per ALT allele.
+///
+/// ```no_run
+/// use vcf2csv::{ ConversionOptions, Converter, Input, Layout, Output, TagSelection };
+///
+/// Converter::new(Input::path("calls.vcf.gz"), Output::path("calls.tsv"))
+///     .with_options(
+///         ConversionOptions::new()
+///             .layout(Layout::Flat)
+///             .info_tags(TagSelection::only(["DP", "AF"]))
+///     )
+///     .run()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Converter {
+    input: Input,
+    output: Output,
+    options: ConversionOptions,
+    checkpoint: Checkpoint,
+}
+
+impl Converter {
+    pub fn new(input: Input, output: Output) -> Self {
+        Converter {
+            input,
+            output,
+            options: ConversionOptions::default(),
+            checkpoint: Checkpoint::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: ConversionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Poll `check` every few thousand records, e.g. for pending signals, and abort the
+    /// conversion with its error. An output file is removed when the conversion fails.
+    pub fn with_cancel_check<F>(mut self, check: F) -> Self
+    where
+        F: FnMut() -> anyhow::Result<()> + Send + 'static,
+    {
+        self.checkpoint.set_cancel(Box::new(check));
+        self
+    }
+
+    /// Call `callback` every `every` records read, and once more when the conversion is done.
+    /// Returning an error aborts the conversion, as for `with_cancel_check`.
+    pub fn with_progress<F>(mut self, every: u64, callback: F) -> Self
+    where
+        F: FnMut(&Progress) -> anyhow::Result<()> + Send + 'static,
+    {
+        self.checkpoint.set_progress(every, Box::new(callback));
+        self
+    }
+
+    pub fn options(&self) -> &ConversionOptions {
+        &self.options
+    }
+
+    pub fn run(self) -> anyhow::Result<ConversionReport> {
+        let Converter { input, output, options, mut checkpoint } = self;
+        let started = Instant::now();
+        if options.schema.is_some() && !schema::describes(options.format, options.genotype_table) {
+            bail!("a schema only describes single tsv, jsonl, pgcopy and pgcopy-binary tables");
+        }
+        if options.create_table.is_some() && !options.format.is_copy() {
+            bail!("create_table only applies to pgcopy and pgcopy-binary output");
+        }
+        if options.ref_alt_columns && options.format.chooses_columns() {
+            bail!(
+                "ref_alt_columns does not apply to {} output, which has columns of its own",
+                options.format
+            );
+        }
+        let indexed = match (&input, options.by_contig) {
+            (_, true) if options.format != OutputFormat::Tsv || options.genotype_table => {
+                bail!("only a single TSV table can be converted by contig")
+            }
+            // htslib crashes rather than fail on a file without an index
+            (Input::Path(path), true) if !has_index(path) => {
+                bail!("converting by contig needs an index, but {} has none", path.display())
+            }
+            (Input::Path(path), true) => Some(path.clone()),
+            (_, true) => bail!("converting by contig needs the path of an indexed file"),
+            (_, false) => None,
+        };
+        let created = match &output {
+            Output::Path(path) => Some(path.clone()),
+            _ => None,
+        };
+
+        let (mut provenance, input, digest) = if options.provenance {
+            let (provenance, input, digest) = Provenance::new(input, &options)?;
+            (Some(provenance), input, digest)
+        } else {
+            (None, input, None)
+        };
+
+        // Only progress reports need to know how far a file is read, and only when it is read
+        // through on this thread rather than by contig workers
+        let track_offset = checkpoint.reports_progress() && indexed.is_none();
+        let (mut source, plan) = open(input, &options, track_offset)?;
+        let header = source.reader.header();
+        // With no contigs in the header there is nothing to fetch, so read the file through
+        let indexed = indexed.filter(|_| header.contig_count() > 0);
+        checkpoint.start(header, source.offset.clone());
+        let mut tally = Tally::new(&plan, header);
+        let definitions = options.schema.as_ref().map(|_| HeaderDefinitions::new(header));
+        let mut metadata = Vec::new();
+        if options.metadata {
+            metadata.extend(plan::meta_lines(header));
+        }
+        metadata.extend(provenance.iter().flat_map(Provenance::entries));
+        let result = convert_into(
+            output,
+            &mut source.reader,
+            indexed.as_deref(),
+            &plan,
+            &metadata,
+            &options,
+            &mut checkpoint,
+            &mut tally,
+        )
+        .map(|_| tally.report(&plan, source.reader.header(), started.elapsed()));
+
+        let result = result.and_then(|mut report| {
+            source.close()?;
+            // A streamed input is only measured, and digested, once read through
+            if let Some(provenance) = &mut provenance {
+                provenance.finish(digest);
+            }
+            if let (Some(path), Some(definitions)) = (&options.schema, &definitions) {
+                let columns = schema::columns(&plan, definitions, &options);
+                let json = schema::to_json(&columns, &options, provenance.as_ref());
+                fs::write(path, json)?;
+            }
+            if let Some(path) = &options.create_table {
+                fs::write(path, postgres::create_table("variants", &plan.columns(), &metadata))?;
+            }
+            report.provenance = provenance;
+            Ok(report)
+        });
+        if let (Err(_), Some(path)) = (&result, created) {
+            if options.format == OutputFormat::Tsv && options.genotype_table {
+                normalized::remove(&path);
+            } else if options.format.is_matrix() {
+                matrix::remove(&path);
+            } else if options.format == OutputFormat::Plink {
+                plink::remove(&path);
+            } else {
+                let _ = fs::remove_file(path);
+            }
+        }
+        result
+    }
+}
+
+/// Whether `path` has a `.tbi` or `.csi` index next to it.
+fn has_index(path: &Path) -> bool {
+    ["tbi", "csi"].iter().any(|extension| {
+        let mut index = path.as_os_str().to_owned();
+        index.push(format!(".{}", extension));
+        Path::new(&index).exists()
+    })
+}
+
+fn open(
+    input: Input,
+    options: &ConversionOptions,
+    offset: bool,
+) -> anyhow::Result<(Source, HeaderPlan)> {
+    let mut source = Source::open(input, offset)?;
+    // Contig workers open their own readers; this one only supplies the header
+    let by_contig = options.by_contig && source.reader.header().contig_count() > 0;
+    if options.threads > 1 && !by_contig {
+        source.reader.set_threads(options.threads)?;
+    }
+    let plan = output_plan(source.reader.header(), options)?;
+    Ok((source, plan))
+}
+
+/// The plan of the columns `options` convert `header` into, chosen by the output format.
+pub(crate) fn output_plan(
+    header: &HeaderView,
+    options: &ConversionOptions,
+) -> anyhow::Result<HeaderPlan> {
+    let plan = if options.format.is_matrix() {
+        matrix::plan(header, &options.matrix_value)?
+    } else if options.format == OutputFormat::Plink {
+        // The genotypes of a dosage matrix, coded for PLINK instead
+        matrix::plan(header, &MatrixValue::Dosage)?
+    } else if options.format == OutputFormat::Maf {
+        maf::plan(header)?
+    } else if matches!(options.format, OutputFormat::Bed | OutputFormat::Bedpe) {
+        bed::plan(header)?
+    } else if options.format == OutputFormat::Somatic {
+        somatic::plan(header, &options.info_tags)?
+    } else {
+        let mut plan = HeaderPlan::new(header, &options.info_tags, &options.format_tags)?;
+        if options.ref_alt_columns {
+            plan.split_ref_alt();
+        } else if options.format == OutputFormat::Tsv && options.genotype_table {
+            plan.split_info_alleles();
+        }
+        plan
+    };
+    Ok(plan)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn convert_into(
+    output: Output,
+    reader: &mut bcf::Reader,
+    indexed: Option<&Path>,
+    plan: &HeaderPlan,
+    metadata: &[(String, String)],
+    options: &ConversionOptions,
+    checkpoint: &mut Checkpoint,
+    tally: &mut Tally,
+) -> anyhow::Result<()> {
+    let bytes: Box<dyn Write> = match output {
+        #[cfg(feature = "sqlite")]
+        Output::Path(path) if options.format == OutputFormat::Sqlite => {
+            let mut db = SqliteWriter::create(path, options.genotype_table)?;
+            return convert_sink(&mut db, reader, plan, metadata, options, checkpoint, tally);
+        }
+        Output::Path(path) if options.format == OutputFormat::Tsv && options.genotype_table => {
+            let samples = HeaderDefinitions::new(reader.header()).samples;
+            let mut tables = NormalizedWriter::create(path, plan, samples)?;
+            return convert_sink(&mut tables, reader, plan, metadata, options, checkpoint, tally);
+        }
+        Output::Path(path) if options.format.is_matrix() => {
+            let npy = options.format == OutputFormat::Npy;
+            let mut matrix = MatrixWriter::create(path, &plan.samples, npy)?;
+            return convert_sink(&mut matrix, reader, plan, metadata, options, checkpoint, tally);
+        }
+        Output::Path(path) if options.format == OutputFormat::Plink => {
+            let samples = HeaderDefinitions::new(reader.header()).samples;
+            let mut fileset = PlinkWriter::create(
+                path,
+                plan.samples.clone(),
+                samples,
+                options.non_diploid,
+                options.multiallelic,
+            )?;
+            return convert_sink(&mut fileset, reader, plan, metadata, options, checkpoint, tally);
+        }
+        Output::Path(path) => Box::new(File::create(path)?),
+        Output::Writer(writer) => writer,
+        Output::Sink(mut sink) => {
+            return convert_sink(sink.as_mut(), reader, plan, metadata, options, checkpoint, tally);
+        }
+    };
+    let bytes = io::BufWriter::new(bytes);
+    match options.format {
+        OutputFormat::Tsv if options.genotype_table => bail!("linked tables need an output path"),
+        OutputFormat::Tsv => {
+            let mut writer = Writer::new(bytes, options.layout);
+            begin(&mut writer, plan, metadata)?;
+            convert_text(reader, indexed, plan, options, writer, checkpoint, tally)
+        }
+        OutputFormat::JsonLines => {
+            let mut writer = JsonLinesWriter::new(bytes);
+            convert_sink(&mut writer, reader, plan, metadata, options, checkpoint, tally)
+        }
+        OutputFormat::Sqlite if cfg!(feature = "sqlite") => bail!("SQLite output needs a path"),
+        OutputFormat::Sqlite => bail!("built without SQLite support"),
+        #[cfg(feature = "xlsx")]
+        OutputFormat::Xlsx => {
+            let mut workbook = XlsxWriter::new(bytes, options.layout, options.sheet_per_contig);
+            convert_sink(&mut workbook, reader, plan, metadata, options, checkpoint, tally)
+        }
+        #[cfg(not(feature = "xlsx"))]
+        OutputFormat::Xlsx => bail!("built without XLSX support"),
+        OutputFormat::PostgresText | OutputFormat::PostgresBinary => {
+            let binary = options.format == OutputFormat::PostgresBinary;
+            let mut writer = PostgresWriter::new(bytes, binary);
+            convert_sink(&mut writer, reader, plan, metadata, options, checkpoint, tally)
+        }
+        OutputFormat::Matrix | OutputFormat::Npy => {
+            bail!("matrix output needs an output path, next to which the row keys are written")
+        }
+        OutputFormat::Plink => bail!("PLINK output needs an output path for its three files"),
+        OutputFormat::Maf => {
+            let header = reader.header();
+            let tumor = options.tumor_sample.as_deref();
+            let (tumor, normal) =
+                plan::tumor_normal(header, tumor, options.normal_sample.as_deref())?;
+            let mut writer = MafWriter::new(bytes, tumor, normal, maf::csq_fields(header));
+            convert_sink(&mut writer, reader, plan, metadata, options, checkpoint, tally)
+        }
+        OutputFormat::Bed | OutputFormat::Bedpe => {
+            let pairs = options.format == OutputFormat::Bedpe;
+            let mut writer = BedWriter::new(bytes, options.bed_name, pairs);
+            convert_sink(&mut writer, reader, plan, metadata, options, checkpoint, tally)
+        }
+        OutputFormat::Somatic => {
+            let tumor = options.tumor_sample.as_deref();
+            let (tumor, normal) =
+                plan::tumor_normal(reader.header(), tumor, options.normal_sample.as_deref())?;
+            let normal = match normal {
+                Some(normal) => normal,
+                None => bail!("the normal sample is neither given nor declared in the header"),
+            };
+            let mut writer = SomaticWriter::new(bytes, tumor, normal);
+            convert_sink(&mut writer, reader, plan, metadata, options, checkpoint, tally)
+        }
+    }
+}
+
+/// Convert on the calling thread, into a backend with its own way of writing rows.
+fn convert_sink<S: RowSink + ?Sized>(
+    sink: &mut S,
+    reader: &mut bcf::Reader,
+    plan: &HeaderPlan,
+    metadata: &[(String, String)],
+    options: &ConversionOptions,
+    checkpoint: &mut Checkpoint,
+    tally: &mut Tally,
+) -> anyhow::Result<()> {
+    begin(sink, plan, metadata)?;
+    convert(reader, plan, options, sink, checkpoint, tally)?;
+    sink.finish()?;
+    checkpoint.finish()
+}
+
+/// Hand the sink its metadata, if any, and columns before the first row.
+fn begin<S: RowSink + ?Sized>(
+    sink: &mut S,
+    plan: &HeaderPlan,
+    metadata: &[(String, String)],
+) -> anyhow::Result<()> {
+    if !metadata.is_empty() {
+        sink.metadata(metadata)?;
+    }
+    sink.begin(&plan.columns())
+}
+
+/// Text output is the one backend whose rows can be formatted off the reading thread.
+fn convert_text<W: Write>(
+    reader: &mut bcf::Reader,
+    indexed: Option<&Path>,
+    plan: &HeaderPlan,
+    options: &ConversionOptions,
+    mut writer: Writer<W>,
+    checkpoint: &mut Checkpoint,
+    tally: &mut Tally,
+) -> anyhow::Result<()> {
+    if let Some(path) = indexed {
+        parallel::convert_by_contig(path, plan, options, &mut writer, checkpoint, tally)?;
+    } else if options.threads > 1 {
+        parallel::convert(reader, plan, options, &mut writer, checkpoint, tally)?;
+    } else {
+        convert(reader, plan, options, &mut writer, checkpoint, tally)?;
+    }
+    writer.finish()?;
+    checkpoint.finish()
+}
+
+fn convert<S: RowSink + ?Sized>(
+    reader: &mut bcf::Reader,
+    plan: &HeaderPlan,
+    options: &ConversionOptions,
+    sink: &mut S,
+    checkpoint: &mut Checkpoint,
+    tally: &mut Tally,
+) -> anyhow::Result<()> {
+    let mut formatter = RowFormatter::new(plan);
+    let mut rec = reader.empty_record();
+    while read_next(reader, &mut rec, options.on_error, tally)? {
+        let rows = formatter.write_record(reader.header(), &rec, sink)?;
+        tally.record(rec.rid(), formatter.buffers(), rows);
+        checkpoint.record(rec.rid(), rec.pos(), rows)?;
+    }
+    Ok(())
+}
+
+/// Unreadable records in a row after which skipping gives up: htslib fails the same way at
+/// every read once its input is truncated or corrupt, rather than moving past the error.
+const MAX_SKIPPED_IN_A_ROW: u64 = 1000;
+
+/// Read the next record into `rec`, applying the error policy and counting the records skipped;
+/// false once the input is exhausted.
+pub(crate) fn read_next<R: Read>(
+    reader: &mut R,
+    rec: &mut bcf::Record,
+    on_error: ErrorPolicy,
+    tally: &mut Tally,
+) -> anyhow::Result<bool> {
+    let mut skipped = 0;
+    loop {
+        match reader.read(rec) {
+            Some(Ok(())) => {
+                return Ok(true);
+            }
+            None => {
+                return Ok(false);
+            }
+            Some(Err(e)) => {
+                match on_error {
+                    ErrorPolicy::Fail => bail!(e),
+                    ErrorPolicy::Skip if skipped == MAX_SKIPPED_IN_A_ROW => {
+                        bail!("{} records in a row are unreadable, the last with: {}", skipped, e)
+                    }
+                    ErrorPolicy::Skip => {
+                        tally.skipped += 1;
+                        skipped += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn refuses_to_convert_by_contig_what_it_cannot_split() {
+        let text = testing::vcf(&["1\t100\t.\tA\tC\t.\t.\t.\tGT\t0/1\t0/0"]);
+        let (_dir, indexed) = testing::indexed_vcf_file(&text);
+        let unindexed = testing::vcf_file(&text);
+        let by_contig = ConversionOptions::new().by_contig(true);
+        let error = |input, options| testing::run(input, options).unwrap_err().to_string();
+        assert_eq!(
+            error(Input::path(&indexed), by_contig.clone().format(OutputFormat::JsonLines)),
+            "only a single TSV table can be converted by contig"
+        );
+        assert!(error(Input::path(unindexed.path()), by_contig.clone()).contains("has none"));
+        assert_eq!(
+            error(Input::reader(io::Cursor::new(text.into_bytes())), by_contig),
+            "converting by contig needs the path of an indexed file"
+        );
+    }
+    #[test]
+    fn removes_the_output_of_a_cancelled_conversion() {
+        let records: Vec<_> = (1..=500)
+            .map(|pos| format!("1\t{}\t.\tA\tC\t.\t.\tDP=1\tGT\t0/1\t0/0", pos))
+            .collect();
+        let text = testing::vcf(&records.iter().map(String::as_str).collect::<Vec<_>>());
+        let file = testing::vcf_file(&text);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("calls.tsv");
+        for threads in [1, 2] {
+            let options = ConversionOptions::new().threads(threads);
+            // First polled after 64 records, with hundreds still to convert
+            let result = Converter::new(Input::path(file.path()), Output::path(&output))
+                .with_options(options)
+                .with_cancel_check(|| bail!("interrupted"))
+                .run();
+            assert_eq!(result.unwrap_err().to_string(), "interrupted");
+            assert!(!output.exists());
+        }
+    }
+    #[test]
+    fn converts_a_stream_into_a_writer_as_it_does_a_file() {
+        let text = testing::vcf(&[
+            "1\t100\t.\tA\tC,G\t50\tPASS\tDP=10;AF=0.25,0.5\tGT:DP\t0/1:7\t1|2:.",
+            "1\t200\t.\tT\tG\t.\t.\t.\tGT\t./.\t0/1",
+        ]);
+        let file = testing::vcf_file(&text);
+        let (expected, _) = testing::run(Input::path(file.path()), ConversionOptions::new()).unwrap();
+        // More than a pipe buffer, so that htslib reads while the stream is still being fed
+        let mut large = text.clone();
+        for pos in 300..5000 {
+            large.push_str(&format!("1\t{}\t.\tA\tC\t.\t.\t.\tGT\t0/1\t0/0\n", pos));
+        }
+        for (input, rows) in [(text, 3), (large, 4703)] {
+            let output = testing::SharedOutput::default();
+            let report = Converter::new(
+                Input::reader(io::Cursor::new(input.into_bytes())),
+                Output::writer(output.clone()),
+            )
+            .run()
+            .unwrap();
+            assert_eq!(report.rows_written, rows);
+            assert!(output.text().starts_with(&expected));
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use rust_htslib::bcf;
+use rust_htslib::bcf::header::{ HeaderView, TagType };
+use rust_htslib::bcf::record::Buffer;
+
+use crate::plan::{ HeaderPlan, TagPlan };
+
+/// htslib's markers for the end of a vector shorter than the space reserved for it.
+const VECTOR_END_INTEGER: i32 = i32::MIN + 1;
+const VECTOR_END_FLOAT: u32 = 0x7F80_0002;
+
+pub(crate) fn trim_ints(values: &[i32]) -> &[i32] {
+    let end = values.iter().position(|&v| v == VECTOR_END_INTEGER).unwrap_or(values.len());
+    &values[..end]
+}
+
+fn trim_floats(values: &[f32]) -> &[f32] {
+    let end = values.iter().position(|v| v.to_bits() == VECTOR_END_FLOAT).unwrap_or(values.len());
+    &values[..end]
+}
+
+/// One INFO tag of the current record.
+///
+/// String values are split on commas, `offsets[j]..offsets[j + 1]` being the range of the
+/// `j`-th one.
+#[derive(Debug, Default)]
+pub struct InfoBuffer {
+    present: bool,
+    ints: Vec<i32>,
+    floats: Vec<f32>,
+    bytes: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl InfoBuffer {
+    fn clear(&mut self) {
+        self.present = false;
+        self.ints.clear();
+        self.floats.clear();
+        self.bytes.clear();
+        self.offsets.clear();
+        self.offsets.push(0);
+    }
+
+    /// Whether the tag is set on the record; the value of a Flag.
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    pub fn int(&self, index: usize) -> Option<i32> {
+        self.ints.get(index).copied()
+    }
+
+    pub fn float(&self, index: usize) -> Option<f32> {
+        self.floats.get(index).copied()
+    }
+
+    pub fn string(&self, index: usize) -> Option<&[u8]> {
+        if index + 1 >= self.offsets.len() {
+            return None;
+        }
+        Some(&self.bytes[self.offsets[index]..self.offsets[index + 1]])
+    }
+
+    pub fn ints(&self) -> &[i32] {
+        trim_ints(&self.ints)
+    }
+
+    pub fn floats(&self) -> &[f32] {
+        trim_floats(&self.floats)
+    }
+
+    pub fn strings(&self) -> impl Iterator<Item = &[u8]> {
+        self.offsets.windows(2).map(|range| &self.bytes[range[0]..range[1]])
+    }
+
+    pub fn decode(
+        &mut self,
+        rec: &bcf::Record,
+        tag: &TagPlan,
+        scratch: &mut Buffer,
+    ) -> anyhow::Result<()> {
+        self.clear();
+        let name = tag.name.as_bytes();
+        match tag.tag_type {
+            TagType::Flag => {
+                self.present = rec.info_shared_buffer(name, scratch).flag()?;
+            }
+            TagType::Integer => {
+                if let Ok(Some(values)) = rec.info_shared_buffer(name, scratch).integer() {
+                    self.ints.extend_from_slice(&values);
+                    self.present = true;
+                }
+            }
+            TagType::Float => {
+                if let Ok(Some(values)) = rec.info_shared_buffer(name, scratch).float() {
+                    self.floats.extend_from_slice(&values);
+                    self.present = true;
+                }
+            }
+            TagType::String => {
+                if let Ok(Some(values)) = rec.info_shared_buffer(name, scratch).string() {
+                    for value in values.iter() {
+                        self.bytes.extend_from_slice(value);
+                        self.offsets.push(self.bytes.len());
+                    }
+                    self.present = true;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One FORMAT tag of the current record, decoded for every sample at once.
+///
+/// Values are stored flat, `offsets[s]..offsets[s + 1]` being the range of sample `s`. The
+/// vectors are cleared between records rather than reallocated.
+#[derive(Debug, Default)]
+pub struct FormatBuffer {
+    present: bool,
+    ints: Vec<i32>,
+    floats: Vec<f32>,
+    bytes: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl FormatBuffer {
+    fn clear(&mut self) {
+        self.present = false;
+        self.ints.clear();
+        self.floats.clear();
+        self.bytes.clear();
+        self.offsets.clear();
+        self.offsets.push(0);
+    }
+
+    fn sample_range(&self, sample: usize) -> Option<std::ops::Range<usize>> {
+        if !self.present || sample + 1 >= self.offsets.len() {
+            return None;
+        }
+        Some(self.offsets[sample]..self.offsets[sample + 1])
+    }
+
+    /// Whether the tag is set on the record, for any sample.
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    pub fn ints(&self, sample: usize) -> Option<&[i32]> {
+        self.sample_range(sample).map(|range| &self.ints[range])
+    }
+
+    pub fn floats(&self, sample: usize) -> Option<&[f32]> {
+        self.sample_range(sample).map(|range| &self.floats[range])
+    }
+
+    pub fn bytes(&self, sample: usize) -> Option<&[u8]> {
+        self.sample_range(sample).map(|range| &self.bytes[range])
+    }
+
+    /// The values of `sample`, without the padding of samples with fewer values than others.
+    pub fn int_values(&self, sample: usize) -> Option<&[i32]> {
+        self.ints(sample).map(trim_ints)
+    }
+
+    /// The values of `sample`, without the padding of samples with fewer values than others.
+    pub fn float_values(&self, sample: usize) -> Option<&[f32]> {
+        self.floats(sample).map(trim_floats)
+    }
+
+    /// Decode `tag` from `rec`; GT is kept as htslib-encoded integers.
+    pub fn decode(&mut self, rec: &bcf::Record, tag: &TagPlan, scratch: &mut Buffer) {
+        self.clear();
+        let name = tag.name.as_bytes();
+        match tag.tag_type {
+            TagType::Integer => {
+                if let Ok(values) = rec.format_shared_buffer(name, scratch).integer() {
+                    for sample in values.iter() {
+                        self.ints.extend_from_slice(sample);
+                        self.offsets.push(self.ints.len());
+                    }
+                    self.present = true;
+                }
+            }
+            TagType::Float => {
+                if let Ok(values) = rec.format_shared_buffer(name, scratch).float() {
+                    for sample in values.iter() {
+                        self.floats.extend_from_slice(sample);
+                        self.offsets.push(self.floats.len());
+                    }
+                    self.present = true;
+                }
+            }
+            TagType::String if tag.is_genotype() => {
+                if let Ok(values) = rec.format_shared_buffer(name, scratch).integer() {
+                    for sample in values.iter() {
+                        self.ints.extend_from_slice(sample);
+                        self.offsets.push(self.ints.len());
+                    }
+                    self.present = true;
+                }
+            }
+            TagType::String => {
+                if let Ok(values) = rec.format_shared_buffer(name, scratch).string() {
+                    for sample in values.iter() {
+                        self.bytes.extend_from_slice(sample);
+                        self.offsets.push(self.bytes.len());
+                    }
+                    self.present = true;
+                }
+            }
+            TagType::Flag => (),
+        }
+    }
+}
+
+/// Everything the output needs from one record, owned and reused from record to record.
+#[derive(Debug, Default)]
+pub struct RecordBuffers {
+    pub chrom: Vec<u8>,
+    /// 0-based position.
+    pub pos: i64,
+    /// The record ID, `.` if it has none.
+    pub id: Vec<u8>,
+    pub qual: f32,
+    /// FILTER names joined by `;`, empty for `.`.
+    pub filters: Vec<u8>,
+    alleles: Vec<u8>,
+    allele_offsets: Vec<usize>,
+    pub info: Vec<InfoBuffer>,
+    pub format: Vec<FormatBuffer>,
+    /// The depth tags of the plan not planned as FORMAT tags, decoded only for derived columns.
+    pub depths: Vec<FormatBuffer>,
+}
+
+impl RecordBuffers {
+    pub fn new(plan: &HeaderPlan) -> Self {
+        RecordBuffers {
+            info: plan.info.iter().map(|_| InfoBuffer::default()).collect(),
+            format: plan.format.iter().map(|_| FormatBuffer::default()).collect(),
+            depths: plan.depth_tags.iter().map(|_| FormatBuffer::default()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Number of alleles, REF included.
+    pub fn allele_count(&self) -> usize {
+        self.allele_offsets.len().saturating_sub(1)
+    }
+
+    /// The `i`-th allele, REF being 0.
+    pub fn allele(&self, i: usize) -> &[u8] {
+        &self.alleles[self.allele_offsets[i]..self.allele_offsets[i + 1]]
+    }
+
+    /// The depth tag `name` the derived columns read: the buffer of the FORMAT tag if it is
+    /// planned, else its own.
+    pub fn depth_tag(&self, plan: &HeaderPlan, name: &str) -> Option<&FormatBuffer> {
+        let i = plan.depth_tags.iter().position(|tag| tag.name == name)?;
+        match plan.format.iter().position(|tag| tag.name == name) {
+            Some(planned) => self.format.get(planned),
+            None => self.depths.get(i),
+        }
+    }
+
+    pub fn decode(
+        &mut self,
+        plan: &HeaderPlan,
+        header: &HeaderView,
+        rec: &bcf::Record,
+        scratch: &mut Buffer,
+    ) -> anyhow::Result<()> {
+        self.chrom.clear();
+        self.chrom.extend_from_slice(header.rid2name(rec.rid().unwrap())?);
+        self.pos = rec.pos();
+        if plan.id {
+            self.id = rec.id();
+        }
+        self.qual = rec.qual();
+
+        self.alleles.clear();
+        self.allele_offsets.clear();
+        self.allele_offsets.push(0);
+        for allele in rec.alleles() {
+            self.alleles.extend_from_slice(allele);
+            self.allele_offsets.push(self.alleles.len());
+        }
+
+        self.filters.clear();
+        for (i, filter) in rec.filters().enumerate() {
+            if i != 0 {
+                self.filters.push(b';');
+            }
+            self.filters.extend_from_slice(&header.id_to_name(filter));
+        }
+
+        for (tag, buffer) in plan.info.iter().zip(self.info.iter_mut()) {
+            buffer.decode(rec, tag, scratch)?;
+        }
+        for (tag, buffer) in plan.format.iter().zip(self.format.iter_mut()) {
+            buffer.decode(rec, tag, scratch);
+        }
+        if !plan.derived.is_empty() {
+            for (tag, buffer) in plan.depth_tags.iter().zip(self.depths.iter_mut()) {
+                if !plan.format.iter().any(|planned| planned.name == tag.name) {
+                    buffer.decode(rec, tag, scratch);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use rust_htslib::bcf;
+use rust_htslib::bcf::header::{ HeaderView, TagType };
+use rust_htslib::bcf::record::{ Buffer, GenotypeAllele, Numeric };
+
+use anyhow::bail;
+
+use crate::decode::RecordBuffers;
+use crate::plan::{ Derived, HeaderPlan };
+use crate::sink::RowSink;
+
+/// Turns VCF records into rows, one per ALT allele, following a `HeaderPlan`.
+pub struct RowFormatter<'a> {
+    plan: &'a HeaderPlan,
+    buffers: RecordBuffers,
+    scratch: Buffer,
+}
+
+impl<'a> RowFormatter<'a> {
+    pub fn new(plan: &'a HeaderPlan) -> Self {
+        RowFormatter {
+            plan,
+            buffers: RecordBuffers::new(plan),
+            scratch: Buffer::new(),
+        }
+    }
+
+    /// The record last passed to `write_record`, decoded.
+    pub fn buffers(&self) -> &RecordBuffers {
+        &self.buffers
+    }
+
+    /// Write one row per ALT allele of `rec`, returning the number of rows the sink kept.
+    pub fn write_record<S: RowSink + ?Sized>(
+        &mut self,
+        header: &HeaderView,
+        rec: &bcf::Record,
+        sink: &mut S,
+    ) -> anyhow::Result<usize> {
+        self.buffers.decode(self.plan, header, rec, &mut self.scratch)?;
+        write_rows(self.plan, &self.buffers, sink)
+    }
+}
+
+/// Write one row per ALT allele of an already decoded record, returning the number of rows the
+/// sink kept.
+pub fn write_rows<S: RowSink + ?Sized>(
+    plan: &HeaderPlan,
+    rec: &RecordBuffers,
+    sink: &mut S,
+) -> anyhow::Result<usize> {
+    let alt_count = rec.allele_count().saturating_sub(1);
+    let dropped = sink.rows_dropped();
+    sink.begin_record()?;
+    for i in 0..alt_count {
+        sink.begin_row()?;
+        sink.write_str(&rec.chrom)?;
+        sink.write_int((rec.pos as i32) + 1)?;
+        if plan.id {
+            match rec.id.as_slice() {
+                b"." => sink.write_null()?,
+                id => sink.write_str(id)?,
+            }
+        }
+        sink.write_str(rec.allele(0))?;
+        sink.write_str(rec.allele(i + 1))?;
+        match rec.qual {
+            q if q.is_missing() => sink.write_null()?,
+            q => sink.write_float(q)?,
+        }
+        if rec.filters.is_empty() {
+            sink.write_null()?;
+        } else {
+            sink.write_str(&rec.filters)?;
+        }
+
+        for (tag, buffer) in plan.info.iter().zip(&rec.info) {
+            for idx in tag.projection.indices(i)? {
+                match tag.tag_type {
+                    TagType::Flag => sink.write_flag(buffer.is_present())?,
+                    _ if tag.is_multi_valued() && !buffer.is_present() => sink.write_null()?,
+                    TagType::Integer if tag.is_multi_valued() => sink.write_ints(buffer.ints())?,
+                    TagType::Float if tag.is_multi_valued() => {
+                        sink.write_floats(buffer.floats())?
+                    }
+                    TagType::String if tag.is_multi_valued() => {
+                        sink.write_strs(&mut buffer.strings())?
+                    }
+                    TagType::Integer => {
+                        match buffer.int(idx) {
+                            Some(v) if !v.is_missing() => sink.write_int(v)?,
+                            _ => sink.write_null()?,
+                        }
+                    }
+                    TagType::Float => {
+                        match buffer.float(idx) {
+                            Some(v) if !v.is_missing() => sink.write_float(v)?,
+                            _ => sink.write_null()?,
+                        }
+                    }
+                    TagType::String => {
+                        match buffer.string(idx) {
+                            Some(v) => sink.write_str(v)?,
+                            None => sink.write_null()?,
+                        }
+                    }
+                }
+            }
+        }
+
+        for s in 0..plan.samples.len() {
+            for (tag, buffer) in plan.format.iter().zip(&rec.format) {
+                for idx in tag.projection.indices(i)? {
+                    match tag.tag_type {
+                        TagType::Flag => {
+                            bail!(
+                                "Unable to find FORMAT \"{0}\" in the input file! Is \"{0}\" an INFO tag?",
+                                tag.name
+                            );
+                        }
+                        TagType::Integer if tag.is_multi_valued() => {
+                            match buffer.int_values(s) {
+                                Some(v) if !v.iter().all(Numeric::is_missing) => {
+                                    sink.write_ints(v)?
+                                }
+                                _ => sink.write_null()?,
+                            }
+                        }
+                        TagType::Float if tag.is_multi_valued() => {
+                            match buffer.float_values(s) {
+                                Some(v) if !v.iter().all(Numeric::is_missing) => {
+                                    sink.write_floats(v)?
+                                }
+                                _ => sink.write_null()?,
+                            }
+                        }
+                        TagType::Integer => {
+                            match buffer.ints(s).and_then(|v| v.get(idx)) {
+                                Some(v) if !v.is_missing() => sink.write_int(*v)?,
+                                _ => sink.write_null()?,
+                            }
+                        }
+                        TagType::Float => {
+                            match buffer.floats(s).and_then(|v| v.get(idx)) {
+                                Some(v) if !v.is_missing() => sink.write_float(*v)?,
+                                _ => sink.write_null()?,
+                            }
+                        }
+                        TagType::String if tag.is_genotype() => {
+                            match buffer.ints(s) {
+                                Some(genotype) => sink.write_genotype(genotype, i + 1)?,
+                                None => sink.write_null()?,
+                            }
+                        }
+                        TagType::String => {
+                            match buffer.bytes(s) {
+                                Some(v) => sink.write_str(v)?,
+                                None => sink.write_null()?,
+                            }
+                        }
+                    }
+                }
+            }
+            if !plan.derived.is_empty() {
+                let depths = Depths::new(plan, rec, s, i + 1);
+                for derived in &plan.derived {
+                    match derived {
+                        Derived::Depth => {
+                            match depths.depth() {
+                                Some(v) => sink.write_int(v)?,
+                                None => sink.write_null()?,
+                            }
+                        }
+                        Derived::Vaf => {
+                            match depths.vaf() {
+                                Some(v) => sink.write_float(v)?,
+                                None => sink.write_null()?,
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        sink.end_row()?;
+    }
+
+    Ok(alt_count - (sink.rows_dropped() - dropped) as usize)
+}
+
+/// The variant allele frequency of a call: the AD of the ALT allele over the sum of AD, else
+/// over DP, or the AF of the ALT allele where neither gives it.
+fn vaf(
+    alt_depth: Option<i32>,
+    allele_depths: Option<i32>,
+    depth: Option<i32>,
+    frequency: Option<f32>,
+) -> Option<f32> {
+    let depth = allele_depths.filter(|&depths| depths > 0).or(depth);
+    match (alt_depth, depth) {
+        (Some(alt_depth), Some(depth)) if depth > 0 => Some(alt_depth as f32 / depth as f32),
+        _ => frequency,
+    }
+}
+
+/// What the depth tags of a sample give for the row of one ALT allele, the source of the
+/// derived columns.
+struct Depths {
+    alt_depth: Option<i32>,
+    /// The sum of AD over all alleles.
+    allele_depths: Option<i32>,
+    depth: Option<i32>,
+    frequency: Option<f32>,
+}
+
+impl Depths {
+    /// The depths of `sample` for `allele` (1-based).
+    fn new(plan: &HeaderPlan, rec: &RecordBuffers, sample: usize, allele: usize) -> Self {
+        let buffer = |name: &str| rec.depth_tag(plan, name);
+        let present = |depth: &&i32| !depth.is_missing();
+        let depths = buffer("AD")
+            .and_then(|ad| ad.int_values(sample))
+            .filter(|depths| depths.iter().any(|depth| !depth.is_missing()));
+        Depths {
+            alt_depth: depths.and_then(|depths| depths.get(allele)).filter(present).copied(),
+            allele_depths: depths.map(|depths| depths.iter().filter(present).sum()),
+            depth: buffer("DP")
+                .and_then(|dp| dp.int_values(sample)?.first())
+                .filter(present)
+                .copied(),
+            frequency: buffer("AF")
+                .and_then(|af| af.float_values(sample)?.get(allele - 1).copied())
+                .filter(|frequency| !frequency.is_missing()),
+        }
+    }
+
+    /// DP, else the sum of AD.
+    fn depth(&self) -> Option<i32> {
+        self.depth.or(self.allele_depths)
+    }
+
+    fn vaf(&self) -> Option<f32> {
+        vaf(self.alt_depth, self.allele_depths, self.depth, self.frequency)
+    }
+}
+
+/// Render the first two alleles of an encoded genotype relative to one ALT allele: `0` for REF,
+/// `1` for `allele`, `.` for any other or missing allele.
+pub(crate) fn split_genotype(encoded: &[i32], allele: usize) -> [u8; 3] {
+    let get = |j: usize| {
+        encoded.get(j).map_or(GenotypeAllele::UnphasedMissing, |&e| GenotypeAllele::from(e))
+    };
+    let code = |a: GenotypeAllele| {
+        match a.index() {
+            Some(0) => b'0',
+            Some(i) if (i as usize) == allele => b'1',
+            _ => b'.',
+        }
+    };
+    let (a1, a2) = (get(0), get(1));
+    let separator = match a2 {
+        GenotypeAllele::Phased(_) | GenotypeAllele::PhasedMissing => b'|',
+        GenotypeAllele::Unphased(_) | GenotypeAllele::UnphasedMissing => b'/',
+    };
+    [code(a1), separator, code(a2)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::TagSelection;
+    use crate::testing;
+    use crate::writer::{ Layout, Writer };
+
+    const MULTIALLELIC: &str = "1\t100\t.\tA\tC,G\t50\tPASS\tDP=10;AF=0.25,0.5;RC=5,3,2;DB\t\
+        GT:AD:DP:PL\t0/1:5,3,2:10:0,1,2,3,4,5\t1|2:.:.:.";
+    const MISSING: &str = "1\t200\trs1\tT\tG\t.\t.\t.\tGT:AD\t./.:.\t0|0:7,0";
+
+    /// The data lines of the flat TSV of `records`, with all tags planned.
+    fn rows(records: &[&str]) -> Vec<String> {
+        let text = testing::vcf(records);
+        let tsv = testing::tsv(&text, &testing::plan(&text), Layout::Flat);
+        tsv.lines().skip(1).map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn writes_one_row_per_alt_allele() {
+        assert_eq!(
+            rows(&[MULTIALLELIC]),
+            [
+                "1\t100\tA\tC\t50\tPASS\t10\t0.25\t3\ttrue\t0/1\t3\t10\t1\t1|.\t\t\t",
+                "1\t100\tA\tG\t50\tPASS\t10\t0.5\t2\ttrue\t0/.\t2\t10\t2\t.|1\t\t\t",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_missing_values_as_empty_fields() {
+        assert_eq!(
+            rows(&[MISSING]),
+            ["1\t200\tT\tG\t\t\t\t\t\tfalse\t./.\t\t\t\t0|0\t0\t\t"]
+        );
+    }
+
+    #[test]
+    fn splits_the_first_alt_allele_and_leaves_missing_depths_empty() {
+        let text = testing::vcf(&["1\t100\t.\tA\tC,G\t.\t.\t.\tGT:DP\t0/1:.\t1/2:7"]);
+        let (header, _) = testing::read(&text);
+        let plan = HeaderPlan::new(
+            &header,
+            &TagSelection::Only(Vec::new()),
+            &TagSelection::only(["GT", "DP"]),
+        )
+        .unwrap();
+        // Before, calls were split against the allele before the row's own, giving `0/.` and
+        // `./.` for C and `0/1` and `1/.` for G, and the missing DP was written as -2147483648
+        assert_eq!(
+            testing::tsv(&text, &plan, Layout::Flat),
+            "CHROM\tPOS\tREF\tALT\tQUAL\tFILTER\ts1_GT\ts1_DP\ts2_GT\ts2_DP\n\
+             1\t100\tA\tC\t\t\t0/1\t\t1/.\t7\n\
+             1\t100\tA\tG\t\t\t0/.\t\t./1\t7\n"
+        );
+    }
+
+    #[test]
+    fn writes_the_id_and_derived_vaf() {
+        let text = testing::vcf(&[MULTIALLELIC, MISSING]);
+        let (header, _) = testing::read(&text);
+        let mut plan = HeaderPlan::new(
+            &header,
+            &TagSelection::only(["RC"]),
+            &TagSelection::only(["AD"]),
+        )
+        .unwrap();
+        plan.id = true;
+        plan.split_ref_alt();
+        assert_eq!(
+            testing::tsv(&text, &plan, Layout::Flat),
+            "CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tRC_REF\tRC_ALT\t\
+             s1_AD_REF\ts1_AD_ALT\ts1_VAF\ts2_AD_REF\ts2_AD_ALT\ts2_VAF\n\
+             1\t100\t\tA\tC\t50\tPASS\t5\t3\t5\t3\t0.3\t\t\t\n\
+             1\t100\t\tA\tG\t50\tPASS\t5\t2\t5\t2\t0.2\t\t\t\n\
+             1\t200\trs1\tT\tG\t\t\t\t\t\t\t\t7\t0\t0\n"
+        );
+
+        // The VAF reads the AD the columns were written from rather than decoding it again
+        let (_, records) = testing::read(&text);
+        let mut formatter = RowFormatter::new(&plan);
+        let mut sink = Writer::new(Vec::new(), Layout::Flat);
+        formatter.write_record(&header, &records[0], &mut sink).unwrap();
+        let ad = plan.depth_tags.iter().position(|tag| tag.name == "AD").unwrap();
+        assert!(formatter.buffers().format[0].is_present());
+        assert!(!formatter.buffers().depths[ad].is_present());
+    }
+
+    #[test]
+    fn derives_the_vaf_from_unselected_tags() {
+        let text = testing::vcf(&["1\t300\t.\tC\tT\t.\t.\t.\tGT:AD:DP\t0/1:3,1:.\t0/1:0,0:5"]);
+        let (header, _) = testing::read(&text);
+        let mut plan = HeaderPlan::new(
+            &header,
+            &TagSelection::Only(Vec::new()),
+            &TagSelection::only(["GT"]),
+        )
+        .unwrap();
+        plan.split_ref_alt();
+        assert_eq!(
+            testing::tsv(&text, &plan, Layout::Flat),
+            "CHROM\tPOS\tREF\tALT\tQUAL\tFILTER\ts1_GT\ts1_VAF\ts2_GT\ts2_VAF\n\
+             1\t300\tC\tT\t\t\t0/1\t0.25\t0/1\t0\n"
+        );
+    }
+
+    #[test]
+    fn derives_the_vaf_from_ad_then_dp_then_af() {
+        assert_eq!(vaf(Some(3), Some(10), Some(20), Some(0.9)), Some(0.3));
+        assert_eq!(vaf(Some(3), Some(0), Some(20), None), Some(0.15));
+        assert_eq!(vaf(Some(3), None, Some(0), Some(0.9)), Some(0.9));
+        assert_eq!(vaf(None, None, Some(20), Some(0.9)), Some(0.9));
+        assert_eq!(vaf(Some(3), None, None, None), None);
+    }
+
+    #[test]
+    fn splits_genotypes_relative_to_one_alt_allele() {
+        let encoded = |alleles: [GenotypeAllele; 2]| alleles.map(i32::from);
+        let genotype = encoded([GenotypeAllele::Unphased(1), GenotypeAllele::Phased(2)]);
+        assert_eq!(&split_genotype(&genotype, 1), b"1|.");
+        assert_eq!(&split_genotype(&genotype, 2), b".|1");
+        let genotype = encoded([GenotypeAllele::Unphased(0), GenotypeAllele::UnphasedMissing]);
+        assert_eq!(&split_genotype(&genotype, 1), b"0/.");
+        assert_eq!(&split_genotype(&genotype[..1], 1), b"0/.");
+    }
+}
@@ -0,0 +1,55 @@
+use rust_htslib::bcf::Read;
+
+use crate::convert::{ self, ConversionOptions, Input };
+use crate::plan::{ Column, HeaderDefinitions };
+use crate::source::Source;
+
+/// What a VCF/BCF header declares, and the table a conversion of it would write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inspection {
+    pub samples: Vec<String>,
+    pub definitions: HeaderDefinitions,
+    pub columns: Vec<Column>,
+    /// The header lines of the output, following `options.layout`.
+    pub header_rows: Vec<Vec<String>>,
+}
+
+/// Read the header of `input`, but none of its records.
+pub fn inspect(input: Input, options: &ConversionOptions) -> anyhow::Result<Inspection> {
+    let source = Source::open(input, false)?;
+    let header = source.reader.header();
+    let plan = convert::output_plan(header, options)?;
+    let columns = plan.columns();
+    Ok(Inspection {
+        header_rows: options.layout.header_rows(&columns),
+        samples: plan.samples,
+        definitions: HeaderDefinitions::new(header),
+        columns,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::writer::Layout;
+
+    #[test]
+    fn lists_the_columns_the_conversion_writes() {
+        let text = testing::vcf(&[
+            "1\t100\t.\tA\tC,G\t50\tPASS\tDP=10;AF=0.25,0.5;RC=1,2,3\tGT:AD:DP\t0/1:7,3,0:10\t1|2:.:.",
+        ]);
+        let file = testing::vcf_file(&text);
+        for layout in [Layout::Grouped, Layout::Flat] {
+            for ref_alt_columns in [false, true] {
+                let options = || ConversionOptions::new().layout(layout).ref_alt_columns(ref_alt_columns);
+                let inspection = inspect(Input::path(file.path()), &options()).unwrap();
+                let (written, _) = testing::run(Input::path(file.path()), options()).unwrap();
+                let lines: Vec<Vec<&str>> = written.lines().map(|line| line.split('\t').collect()).collect();
+                let (header, rows) = lines.split_at(inspection.header_rows.len());
+                assert_eq!(header, inspection.header_rows);
+                assert!(rows.iter().all(|row| row.len() == inspection.columns.len()));
+            }
+        }
+    }
+}
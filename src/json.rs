@@ -0,0 +1,119 @@
+use std::fmt::{ self, Write };
+
+/// A JSON document, built in memory for the small files written next to the output.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Int(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<I, K>(members: I) -> Self
+    where
+        I: IntoIterator<Item = (K, Json)>,
+        K: Into<String>,
+    {
+        Json::Object(members.into_iter().map(|(key, value)| (key.into(), value)).collect())
+    }
+
+    /// Render with two-space indentation, as the files are meant to be read by people too.
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, 0).expect("writing to a String cannot fail");
+        out.push('\n');
+        out
+    }
+
+    fn write(&self, out: &mut String, indent: usize) -> fmt::Result {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Int(value) => write!(out, "{}", value)?,
+            Json::String(value) => push_string(out, value),
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    separate(out, i, indent + 1);
+                    item.write(out, indent + 1)?;
+                }
+                close(out, items.is_empty(), indent);
+                out.push(']');
+            }
+            Json::Object(members) => {
+                out.push('{');
+                for (i, (key, value)) in members.iter().enumerate() {
+                    separate(out, i, indent + 1);
+                    push_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent + 1)?;
+                }
+                close(out, members.is_empty(), indent);
+                out.push('}');
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<&String> for Json {
+    fn from(value: &String) -> Self {
+        Json::String(value.clone())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+fn separate(out: &mut String, index: usize, indent: usize) {
+    if index > 0 {
+        out.push(',');
+    }
+    newline(out, indent);
+}
+
+fn close(out: &mut String, empty: bool, indent: usize) {
+    if !empty {
+        newline(out, indent);
+    }
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    out.push_str(&"  ".repeat(indent));
+}
+
+/// Append `value` as a JSON string literal.
+pub(crate) fn push_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
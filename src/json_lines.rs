@@ -0,0 +1,272 @@
+use rust_htslib::bcf::record::Numeric;
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::json::push_string;
+use crate::plan::{ Column, ColumnKind };
+use crate::sink::RowSink;
+
+/// Writes one JSON object per row: the site fields, an `info` object and a `samples` object
+/// holding one object of FORMAT values per sample.
+///
+/// Values keep their types: numbers, booleans for flags, `null` when missing and arrays for
+/// tags with several values per row.
+pub struct JsonLinesWriter<W: Write> {
+    inner: W,
+    /// What goes before each value: its key, and the objects opened or closed since the last.
+    prefixes: Vec<String>,
+    /// What closes a row after the last value.
+    suffix: String,
+    line: String,
+    column: usize,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    pub fn new(inner: W) -> Self {
+        JsonLinesWriter {
+            inner,
+            prefixes: Vec::new(),
+            suffix: String::new(),
+            line: String::new(),
+            column: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn value(&mut self) -> &mut String {
+        self.line.push_str(&self.prefixes[self.column]);
+        self.column += 1;
+        &mut self.line
+    }
+}
+
+/// The nesting level of a column within a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Section {
+    Site,
+    Info,
+    Samples,
+}
+
+/// Tracks the objects open while laying out the keys of a row.
+struct Nesting<'a> {
+    section: Section,
+    sample: Option<&'a str>,
+    first: bool,
+}
+
+impl<'a> Nesting<'a> {
+    /// Close and open objects up to `section`, and `sample` within it.
+    fn enter(&mut self, out: &mut String, section: Section, sample: Option<&'a str>) {
+        while self.section < section {
+            self.section = match self.section {
+                Section::Site => {
+                    out.push_str(",\"info\":{");
+                    Section::Info
+                }
+                _ => {
+                    out.push_str("},\"samples\":{");
+                    Section::Samples
+                }
+            };
+            self.first = true;
+        }
+        if sample.is_some() && sample != self.sample {
+            if self.sample.is_some() {
+                out.push_str("},");
+            }
+            push_string(out, sample.unwrap_or_default());
+            out.push_str(":{");
+            self.sample = sample;
+            self.first = true;
+        }
+    }
+
+    fn key(&mut self, out: &mut String, name: &str) {
+        if !self.first {
+            out.push(',');
+        }
+        push_string(out, name);
+        out.push(':');
+        self.first = false;
+    }
+}
+
+impl<W: Write> RowSink for JsonLinesWriter<W> {
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        let mut nesting = Nesting { section: Section::Site, sample: None, first: true };
+        let mut prefix = String::from("{");
+        for column in columns {
+            match &column.kind {
+                ColumnKind::Fixed => (),
+                ColumnKind::Info => nesting.enter(&mut prefix, Section::Info, None),
+                ColumnKind::Format { sample } => {
+                    nesting.enter(&mut prefix, Section::Samples, Some(sample))
+                }
+            }
+            nesting.key(&mut prefix, &column.name);
+            self.prefixes.push(std::mem::take(&mut prefix));
+        }
+        nesting.enter(&mut prefix, Section::Samples, None);
+        if nesting.sample.is_some() {
+            prefix.push('}');
+        }
+        prefix.push_str("}}\n");
+        self.suffix = prefix;
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.line.clear();
+        self.column = 0;
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        write!(self.value(), "{}", value)?;
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        push_float(self.value(), value);
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        push_string(self.value(), &String::from_utf8_lossy(value));
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        self.value().push_str("null");
+        Ok(())
+    }
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()> {
+        self.value().push_str(if value { "true" } else { "false" });
+        Ok(())
+    }
+
+    fn write_ints(&mut self, values: &[i32]) -> anyhow::Result<()> {
+        let out = self.value();
+        out.push('[');
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            if value.is_missing() {
+                out.push_str("null");
+            } else {
+                write!(out, "{}", value)?;
+            }
+        }
+        out.push(']');
+        Ok(())
+    }
+
+    fn write_floats(&mut self, values: &[f32]) -> anyhow::Result<()> {
+        let out = self.value();
+        out.push('[');
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            push_float(out, *value);
+        }
+        out.push(']');
+        Ok(())
+    }
+
+    fn write_strs(&mut self, values: &mut dyn Iterator<Item = &[u8]>) -> anyhow::Result<()> {
+        let out = self.value();
+        out.push('[');
+        for (i, value) in values.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            push_string(out, &String::from_utf8_lossy(value));
+        }
+        out.push(']');
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        self.line.push_str(&self.suffix);
+        self.inner.write_all(self.line.as_bytes())?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// JSON has no NaN or infinities; htslib's missing value is a NaN too.
+fn push_float(out: &mut String, value: f32) {
+    if value.is_finite() {
+        let _ = write!(out, "{}", value);
+    } else {
+        out.push_str("null");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{ HeaderPlan, TagSelection };
+    use crate::testing;
+
+    /// The lines of the VCF `text` with the INFO tags `info` and FORMAT tags `format` planned.
+    fn lines(text: &str, info: &[&str], format: &[&str]) -> Vec<String> {
+        let writer = testing::written(
+            text,
+            |header| {
+                let info = TagSelection::only(info.iter().copied());
+                let format = TagSelection::only(format.iter().copied());
+                HeaderPlan::new(header, &info, &format).unwrap()
+            },
+            |_| JsonLinesWriter::new(Vec::new()),
+        );
+        String::from_utf8(writer.into_inner()).unwrap().lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn nests_info_and_samples() {
+        let text = testing::vcf(&["1\t100\trs1\tA\tC\t50\tPASS\tDP=10;AF=0.25;DB\t\
+            GT:AD:DP\t0/1:5,3:8\t1|1:0,7:7"]);
+        assert_eq!(
+            lines(&text, &["DP", "AF", "DB"], &["GT", "AD"]),
+            [r#"{"CHROM":"1","POS":100,"REF":"A","ALT":"C","QUAL":50,"FILTER":"PASS","#.to_owned()
+                + r#""info":{"DP":10,"AF":0.25,"DB":true},"#
+                + r#""samples":{"s1":{"GT":"0/1","AD":3},"s2":{"GT":"1|1","AD":7}}}"#]
+        );
+        // Objects without a tag are left empty
+        let site = r#"{"CHROM":"1","POS":100,"REF":"A","ALT":"C","QUAL":50,"FILTER":"PASS","#;
+        assert_eq!(
+            lines(&text, &[], &["GT"]),
+            [site.to_owned() + r#""info":{},"samples":{"s1":{"GT":"0/1"},"s2":{"GT":"1|1"}}}"#]
+        );
+        assert_eq!(
+            lines(&text, &["DB"], &[]),
+            [site.to_owned() + r#""info":{"DB":true},"samples":{}}"#]
+        );
+    }
+
+    #[test]
+    fn writes_missing_values_as_null_and_several_as_arrays() {
+        let text = testing::multi_valued_vcf(&[
+            "1\t200\t.\tT\tG\t.\t.\tMQ=3,.;XS=a,b\tGT:HQ\t./.:1.5,.\t.:.",
+        ]);
+        assert_eq!(
+            lines(&text, &["DP", "DB", "MQ", "XS"], &["GT", "HQ"]),
+            [r#"{"CHROM":"1","POS":200,"REF":"T","ALT":"G","QUAL":null,"FILTER":null,"#
+                .to_owned()
+                + r#""info":{"DP":null,"DB":false,"MQ":[3,null],"XS":["a","b"]},"#
+                + r#""samples":{"s1":{"GT":"./.","HQ":[1.5,null]},"s2":{"GT":"./.","HQ":null}}}"#]
+        );
+    }
+}
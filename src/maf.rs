@@ -0,0 +1,475 @@
+use rust_htslib::bcf::header::HeaderView;
+use rust_htslib::bcf::record::GenotypeAllele;
+use std::io::Write;
+
+use crate::decode::trim_ints;
+use crate::plan::{ Column, ColumnKind, Derived, HeaderDefinitions, HeaderPlan };
+use crate::plan::{ TagDefinition, TagSelection };
+use crate::sink::RowSink;
+use crate::writer::{ Layout, Writer };
+
+/// The first line of a MAF file.
+const VERSION_LINE: &[u8] = b"#version 2.4\n";
+
+/// The columns written, those of the MAF specification a VCF has values for, then FILTER.
+pub const MAF_COLUMNS: &[&str] = &[
+    "Hugo_Symbol",
+    "Chromosome",
+    "Start_Position",
+    "End_Position",
+    "Strand",
+    "Variant_Classification",
+    "Variant_Type",
+    "Reference_Allele",
+    "Tumor_Seq_Allele1",
+    "Tumor_Seq_Allele2",
+    "Tumor_Sample_Barcode",
+    "Matched_Norm_Sample_Barcode",
+    "t_depth",
+    "t_ref_count",
+    "t_alt_count",
+    "n_depth",
+    "n_ref_count",
+    "n_alt_count",
+    "FILTER",
+];
+
+/// The plan of a MAF: the site fields, the VEP `CSQ` annotation, and the GT, the REF and ALT
+/// AD and the depth of every sample.
+pub(crate) fn plan(header: &HeaderView) -> anyhow::Result<HeaderPlan> {
+    let definitions = HeaderDefinitions::new(header);
+    let declared = |tags: &[TagDefinition], names: &[&str]| {
+        let names = names.iter().filter(|name| tags.iter().any(|tag| tag.id == **name));
+        TagSelection::only(names.copied())
+    };
+    let info = declared(&definitions.info, &["CSQ"]);
+    let format = declared(&definitions.format, &["GT", "AD"]);
+    let mut plan = HeaderPlan::new(header, &info, &format)?;
+    plan.split_allele_depths();
+    plan.derived = vec![Derived::Depth];
+    Ok(plan)
+}
+
+/// The fields of `CSQ` entries, as listed in the description VEP gives the tag.
+pub fn csq_fields(header: &HeaderView) -> Vec<String> {
+    HeaderDefinitions::new(header)
+        .info
+        .iter()
+        .find(|tag| tag.id == "CSQ")
+        .and_then(|tag| tag.description.split_once("Format: "))
+        .map(|(_, format)| format.split('|').map(|field| field.trim().to_owned()).collect())
+        .unwrap_or_default()
+}
+
+/// What a value of the planned row is used for; calls are indexed tumor first, then normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Chrom,
+    Pos,
+    Ref,
+    Alt,
+    Filter,
+    Csq,
+    Genotype(usize),
+    AltDepth(usize),
+    RefDepth(usize),
+    Depth(usize),
+    Unused,
+}
+
+/// What the row of an ALT allele says about the tumor or normal sample.
+#[derive(Debug, Default)]
+struct Call {
+    /// Whether GT holds only the ALT allele of the row.
+    homozygous_alt: bool,
+    ref_depth: Option<i32>,
+    alt_depth: Option<i32>,
+    depth: Option<i32>,
+}
+
+/// Writes rows as a Mutation Annotation Format table, one line per ALT allele of a tumor sample
+/// and its matched normal, if any.
+///
+/// Positions follow MAF conventions: insertions span the bases either side of the inserted
+/// sequence and deletions the deleted bases, with `-` for the empty allele. The gene and
+/// variant classification come from the VEP `CSQ` entry of the allele, preferring the one
+/// flagged `PICK`, then the canonical transcript.
+pub struct MafWriter<W: Write> {
+    writer: Writer<W>,
+    tumor: String,
+    normal: Option<String>,
+    csq_fields: Vec<String>,
+    fields: Vec<Field>,
+    /// Index of the next value in the current row.
+    column: usize,
+    started: bool,
+    chrom: Vec<u8>,
+    pos: i64,
+    reference: Vec<u8>,
+    alt: Vec<u8>,
+    filter: Vec<u8>,
+    csq: Vec<Vec<u8>>,
+    calls: [Call; 2],
+}
+
+impl<W: Write> MafWriter<W> {
+    /// Write the calls of `tumor` and `normal`, reading `CSQ` entries as `csq_fields`.
+    pub fn new(
+        inner: W,
+        tumor: String,
+        normal: Option<String>,
+        csq_fields: Vec<String>,
+    ) -> Self {
+        MafWriter {
+            writer: Writer::new(inner, Layout::Flat),
+            tumor,
+            normal,
+            csq_fields,
+            fields: Vec::new(),
+            column: 0,
+            started: false,
+            chrom: Vec::new(),
+            pos: 0,
+            reference: Vec::new(),
+            alt: Vec::new(),
+            filter: Vec::new(),
+            csq: Vec::new(),
+            calls: Default::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+
+    fn start(&mut self) -> anyhow::Result<()> {
+        if !self.started {
+            self.writer.append(VERSION_LINE)?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    fn next_field(&mut self) -> Field {
+        let field = self.fields.get(self.column).copied().unwrap_or(Field::Unused);
+        self.column += 1;
+        field
+    }
+
+    fn write_optional(&mut self, value: Option<i32>) -> anyhow::Result<()> {
+        match value {
+            Some(value) => self.writer.write_int(value),
+            None => self.writer.write_null(),
+        }
+    }
+}
+
+impl<W: Write> RowSink for MafWriter<W> {
+    fn metadata(&mut self, entries: &[(String, String)]) -> anyhow::Result<()> {
+        self.start()?;
+        self.writer.metadata(entries)
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        self.start()?;
+        self.fields = columns
+            .iter()
+            .map(|column| {
+                let call = match &column.kind {
+                    ColumnKind::Format { sample } if *sample == self.tumor => Some(0),
+                    ColumnKind::Format { sample } if self.normal.as_ref() == Some(sample) => {
+                        Some(1)
+                    }
+                    _ => None,
+                };
+                match (&column.kind, column.name.as_str(), call) {
+                    (ColumnKind::Fixed, "CHROM", _) => Field::Chrom,
+                    (ColumnKind::Fixed, "POS", _) => Field::Pos,
+                    (ColumnKind::Fixed, "REF", _) => Field::Ref,
+                    (ColumnKind::Fixed, "ALT", _) => Field::Alt,
+                    (ColumnKind::Fixed, "FILTER", _) => Field::Filter,
+                    (ColumnKind::Info, "CSQ", _) => Field::Csq,
+                    (_, "GT", Some(call)) => Field::Genotype(call),
+                    (_, "AD_REF", Some(call)) => Field::RefDepth(call),
+                    (_, "AD_ALT", Some(call)) => Field::AltDepth(call),
+                    (_, "depth", Some(call)) => Field::Depth(call),
+                    _ => Field::Unused,
+                }
+            })
+            .collect();
+        for name in MAF_COLUMNS {
+            self.writer.write_field(name.as_bytes())?;
+        }
+        self.writer.newline()
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.column = 0;
+        self.filter.clear();
+        self.csq.clear();
+        self.calls = Default::default();
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        match self.next_field() {
+            Field::Pos => self.pos = value.into(),
+            Field::RefDepth(call) => self.calls[call].ref_depth = Some(value),
+            Field::AltDepth(call) => self.calls[call].alt_depth = Some(value),
+            Field::Depth(call) => self.calls[call].depth = Some(value),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn write_float(&mut self, _value: f32) -> anyhow::Result<()> {
+        self.next_field();
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        let target = match self.next_field() {
+            Field::Chrom => &mut self.chrom,
+            Field::Ref => &mut self.reference,
+            Field::Alt => &mut self.alt,
+            Field::Filter => &mut self.filter,
+            Field::Csq => {
+                self.csq.push(value.to_vec());
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        target.clear();
+        target.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn write_strs(&mut self, values: &mut dyn Iterator<Item = &[u8]>) -> anyhow::Result<()> {
+        if self.next_field() == Field::Csq {
+            self.csq.extend(values.map(<[u8]>::to_vec));
+        }
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        self.next_field();
+        Ok(())
+    }
+
+    fn write_flag(&mut self, _value: bool) -> anyhow::Result<()> {
+        self.next_field();
+        Ok(())
+    }
+
+    fn write_genotype(&mut self, encoded: &[i32], allele: usize) -> anyhow::Result<()> {
+        if let Field::Genotype(call) = self.next_field() {
+            let alleles = trim_ints(encoded);
+            self.calls[call].homozygous_alt = !alleles.is_empty()
+                && alleles.iter().all(|&a| {
+                    GenotypeAllele::from(a).index().is_some_and(|i| i as usize == allele)
+                });
+        }
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        let (start, end, reference, alt) = maf_alleles(self.pos, &self.reference, &self.alt);
+        let variant_type = variant_type(reference, alt);
+
+        let entries: Vec<Vec<&[u8]>> =
+            self.csq.iter().map(|entry| entry.split(|&b| b == b'|').collect()).collect();
+        let entry = pick(&self.csq_fields, &entries, alt);
+        let annotation = |name: &str| {
+            let i = self.csq_fields.iter().position(|field| field == name)?;
+            entry?.get(i).copied().filter(|value| !value.is_empty())
+        };
+        let symbol = annotation("SYMBOL").unwrap_or(b"Unknown");
+        let classification = classify(annotation("Consequence").unwrap_or_default(), variant_type);
+
+        let tumor_allele = if self.calls[0].homozygous_alt { alt } else { reference };
+        let (start, end) = (start.to_string(), end.to_string());
+        let fields: [&[u8]; 12] = [
+            symbol,
+            &self.chrom,
+            start.as_bytes(),
+            end.as_bytes(),
+            b"+",
+            classification.as_bytes(),
+            variant_type.as_bytes(),
+            reference,
+            tumor_allele,
+            alt,
+            self.tumor.as_bytes(),
+            self.normal.as_deref().unwrap_or_default().as_bytes(),
+        ];
+        for field in fields {
+            self.writer.write_field(field)?;
+        }
+        for call in 0..2 {
+            let call = &self.calls[call];
+            let (depth, ref_depth, alt_depth) = (call.depth, call.ref_depth, call.alt_depth);
+            self.write_optional(depth)?;
+            self.write_optional(ref_depth)?;
+            self.write_optional(alt_depth)?;
+        }
+        self.writer.write_field(if self.filter.is_empty() { b"." } else { &self.filter })?;
+        self.writer.newline()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.finish()
+    }
+}
+
+/// The start, end, REF and ALT of an allele in MAF conventions, from its VCF position.
+fn maf_alleles<'a>(
+    pos: i64,
+    reference: &'a [u8],
+    alt: &'a [u8],
+) -> (i64, i64, &'a [u8], &'a [u8]) {
+    let shared_base = reference.len() != alt.len() && reference.first() == alt.first();
+    if !shared_base {
+        return (pos, pos + reference.len() as i64 - 1, reference, alt);
+    }
+    match (&reference[1..], &alt[1..]) {
+        // An insertion sits between the shared base and the next
+        ([], inserted) => (pos, pos + 1, b"-", inserted),
+        (deleted, []) => (pos + 1, pos + deleted.len() as i64, deleted, b"-"),
+        (reference, alt) => (pos + 1, pos + reference.len() as i64, reference, alt),
+    }
+}
+
+fn variant_type(reference: &[u8], alt: &[u8]) -> &'static str {
+    match (reference, alt) {
+        (b"-", _) => "INS",
+        (_, b"-") => "DEL",
+        _ if reference.len() < alt.len() => "INS",
+        _ if reference.len() > alt.len() => "DEL",
+        _ => {
+            match reference.len() {
+                1 => "SNP",
+                2 => "DNP",
+                3 => "TNP",
+                _ => "ONP",
+            }
+        }
+    }
+}
+
+/// The `CSQ` entry of `allele`: the one flagged `PICK`, else the canonical one, else the first.
+fn pick<'a>(
+    fields: &[String],
+    entries: &'a [Vec<&'a [u8]>],
+    allele: &[u8],
+) -> Option<&'a [&'a [u8]]> {
+    let field = |name: &str| fields.iter().position(|field| field == name);
+    let value = |entry: &&Vec<&'a [u8]>, i: Option<usize>| i.and_then(|i| entry.get(i).copied());
+    let (allele_field, pick_field, canonical_field) =
+        (field("Allele"), field("PICK"), field("CANONICAL"));
+    let candidates: Vec<&Vec<&[u8]>> = entries
+        .iter()
+        .filter(|entry| allele_field.is_none() || value(entry, allele_field) == Some(allele))
+        .collect();
+    candidates
+        .iter()
+        .find(|entry| value(entry, pick_field) == Some(b"1"))
+        .or_else(|| candidates.iter().find(|entry| value(entry, canonical_field) == Some(b"YES")))
+        .or_else(|| candidates.first())
+        .map(|entry| entry.as_slice())
+}
+
+/// The MAF Variant_Classification of the most severe of the `&`-joined VEP consequences.
+fn classify(consequence: &[u8], variant_type: &str) -> &'static str {
+    let term = consequence.split(|&b| b == b'&').next().unwrap_or_default();
+    let insertion = variant_type == "INS";
+    match term {
+        b"" => "",
+        b"splice_acceptor_variant" | b"splice_donor_variant" | b"transcript_ablation"
+        | b"exon_loss_variant" => "Splice_Site",
+        b"stop_gained" => "Nonsense_Mutation",
+        b"frameshift_variant" if insertion => "Frame_Shift_Ins",
+        b"frameshift_variant" => "Frame_Shift_Del",
+        b"stop_lost" => "Nonstop_Mutation",
+        b"start_lost" | b"initiator_codon_variant" => "Translation_Start_Site",
+        b"inframe_insertion" => "In_Frame_Ins",
+        b"inframe_deletion" => "In_Frame_Del",
+        b"protein_altering_variant" if insertion => "In_Frame_Ins",
+        b"protein_altering_variant" if variant_type == "DEL" => "In_Frame_Del",
+        b"missense_variant" | b"coding_sequence_variant" | b"protein_altering_variant"
+        | b"conservative_missense_variant" | b"rare_amino_acid_variant" => "Missense_Mutation",
+        b"transcript_amplification" | b"splice_region_variant" | b"intron_variant"
+        | b"intragenic_variant" | b"INTRAGENIC" => "Intron",
+        b"incomplete_terminal_codon_variant" | b"synonymous_variant" | b"stop_retained_variant"
+        | b"NMD_transcript_variant" => "Silent",
+        b"mature_miRNA_variant" | b"exon_variant" | b"non_coding_exon_variant"
+        | b"non_coding_transcript_exon_variant" | b"non_coding_transcript_variant"
+        | b"nc_transcript_variant" => "RNA",
+        b"5_prime_UTR_variant" | b"5_prime_UTR_premature_start_codon_gain_variant" => "5'UTR",
+        b"3_prime_UTR_variant" => "3'UTR",
+        b"TF_binding_site_variant" | b"regulatory_region_variant" | b"regulatory_region"
+        | b"intergenic_variant" | b"intergenic_region" => "IGR",
+        b"upstream_gene_variant" => "5'Flank",
+        b"downstream_gene_variant" => "3'Flank",
+        _ => "Targeted_Region",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn places_alleles_in_maf_coordinates() {
+        assert_eq!(maf_alleles(100, b"A", b"G"), (100, 100, &b"A"[..], &b"G"[..]));
+        assert_eq!(maf_alleles(100, b"AC", b"GT"), (100, 101, &b"AC"[..], &b"GT"[..]));
+        // The inserted bases sit between POS and the next base
+        assert_eq!(maf_alleles(100, b"T", b"TCA"), (100, 101, &b"-"[..], &b"CA"[..]));
+        // The deleted bases follow the shared base at POS
+        assert_eq!(maf_alleles(100, b"GTC", b"G"), (101, 102, &b"TC"[..], &b"-"[..]));
+        assert_eq!(maf_alleles(100, b"ACG", b"AT"), (101, 102, &b"CG"[..], &b"T"[..]));
+        assert_eq!(maf_alleles(100, b"AC", b"G"), (100, 101, &b"AC"[..], &b"G"[..]));
+    }
+
+    #[test]
+    fn classifies_variant_types() {
+        assert_eq!(variant_type(b"A", b"G"), "SNP");
+        assert_eq!(variant_type(b"AC", b"GT"), "DNP");
+        assert_eq!(variant_type(b"ACG", b"GTA"), "TNP");
+        assert_eq!(variant_type(b"ACGT", b"GTAC"), "ONP");
+        assert_eq!(variant_type(b"-", b"CA"), "INS");
+        assert_eq!(variant_type(b"TC", b"-"), "DEL");
+        assert_eq!(variant_type(b"CG", b"T"), "DEL");
+    }
+
+    #[test]
+    fn writes_tumor_alleles_and_counts() {
+        let text = testing::vcf(&[
+            "1\t100\t.\tA\tG\t.\tPASS\t.\tGT:AD:DP\t1/1:0,9:9\t0/0:8,0:.",
+            "1\t200\t.\tT\tTCA\t.\t.\t.\tGT:AD\t0/1:5,4\t0/0:7,0",
+            "1\t300\t.\tGTC\tG\t.\t.\t.\tGT:AD\t0/1:3,3\t0/0:.",
+            "1\t400\t.\tA\tC,G\t.\t.\t.\tGT:AD\t1/2:2,5,3\t0/0:9,0,0",
+        ]);
+        let writer = testing::written(
+            &text,
+            |header| plan(header).unwrap(),
+            |_| MafWriter::new(Vec::new(), "s1".into(), Some("s2".into()), Vec::new()),
+        );
+        let maf = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines = maf.lines();
+        assert_eq!(lines.next(), Some("#version 2.4"));
+        assert_eq!(lines.next(), Some(MAF_COLUMNS.join("\t").as_str()));
+        // Tumor_Seq_Allele1 is the REF allele unless the tumor is homozygous for the ALT, and
+        // without DP the depth is the sum of AD over every allele
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            [
+                "Unknown\t1\t100\t100\t+\t\tSNP\tA\tG\tG\ts1\ts2\t9\t0\t9\t8\t8\t0\tPASS",
+                "Unknown\t1\t200\t201\t+\t\tINS\t-\t-\tCA\ts1\ts2\t9\t5\t4\t7\t7\t0\t.",
+                "Unknown\t1\t301\t302\t+\t\tDEL\tTC\tTC\t-\ts1\ts2\t6\t3\t3\t\t\t\t.",
+                "Unknown\t1\t400\t400\t+\t\tSNP\tA\tA\tC\ts1\ts2\t10\t2\t5\t9\t9\t0\t.",
+                "Unknown\t1\t400\t400\t+\t\tSNP\tA\tA\tG\ts1\ts2\t10\t2\t3\t9\t9\t0\t.",
+            ]
+        );
+    }
+}
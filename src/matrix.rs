@@ -0,0 +1,352 @@
+use itertools::Itertools;
+use rust_htslib::bcf::header::{ HeaderView, TagType };
+use rust_htslib::bcf::record::GenotypeAllele;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ self, Seek, SeekFrom, Write };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+
+use crate::decode::trim_ints;
+use crate::plan::{ Column, ColumnKind, HeaderPlan, TagSelection };
+use crate::sink::RowSink;
+use crate::writer::{ Layout, Writer };
+use crate::ParseError;
+
+/// Site fields identifying a matrix row, the first of every row.
+const ROW_KEYS: usize = 4;
+/// Size of the `.npy` header, enough for any shape so it can be rewritten in place.
+const NPY_HEADER_SIZE: usize = 128;
+
+/// What each cell of a genotype matrix holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MatrixValue {
+    /// Copies of the ALT allele of the row in GT: 0, 1 or 2 for a diploid sample.
+    #[default]
+    Dosage,
+    /// The value of a numeric FORMAT tag, e.g. `DP` or `GQ`.
+    Tag(String),
+}
+
+impl MatrixValue {
+    /// The FORMAT tag the value is read from.
+    fn tag(&self) -> &str {
+        match self {
+            MatrixValue::Dosage => "GT",
+            MatrixValue::Tag(tag) => tag,
+        }
+    }
+}
+
+impl FromStr for MatrixValue {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dosage" => Ok(MatrixValue::Dosage),
+            _ => Ok(MatrixValue::Tag(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for MatrixValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatrixValue::Dosage => f.write_str("dosage"),
+            MatrixValue::Tag(tag) => f.write_str(tag),
+        }
+    }
+}
+
+/// The plan of a matrix of `value`: the site fields and the one FORMAT tag it is read from.
+pub(crate) fn plan(header: &HeaderView, value: &MatrixValue) -> anyhow::Result<HeaderPlan> {
+    let format_tags = TagSelection::only([value.tag()]);
+    let plan = HeaderPlan::new(header, &TagSelection::Only(Vec::new()), &format_tags)?;
+    let tag = &plan.format[0];
+    let numeric = matches!(tag.tag_type, TagType::Integer | TagType::Float);
+    if *value != MatrixValue::Dosage && !numeric {
+        anyhow::bail!("a matrix holds numbers, but FORMAT {} is not numeric", tag.name);
+    }
+    Ok(plan)
+}
+
+/// The files written next to the matrix at `path`: the row keys, and the sample names that
+/// head the columns of a `.npy` matrix.
+pub fn companion_paths(path: &Path) -> (PathBuf, PathBuf) {
+    (path.with_extension("rows.tsv"), path.with_extension("samples.txt"))
+}
+
+/// Writes rows as a dense matrix of one value per ALT allele (rows) and sample (columns), with
+/// the CHROM, POS, REF and ALT of each row in a separate table of row keys.
+///
+/// The matrix is either tab-separated, headed by the sample names, with `NA` for missing values,
+/// or a NumPy `.npy` array of 32-bit floats, with NaN for missing values.
+pub struct MatrixWriter<W: Write + Seek> {
+    matrix: W,
+    row_keys: Writer<W>,
+    npy: bool,
+    /// Site columns before the first sample value.
+    site_columns: usize,
+    samples: usize,
+    /// Index of the next value in the current row.
+    column: usize,
+    rows: u64,
+}
+
+impl MatrixWriter<io::BufWriter<File>> {
+    /// Create the matrix at `path`, and the files `companion_paths` names next to it.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_names: &[String],
+        npy: bool,
+    ) -> anyhow::Result<Self> {
+        let (rows_path, samples_path) = companion_paths(path.as_ref());
+        if npy {
+            let lines: String = sample_names.iter().map(|name| format!("{}\n", name)).collect();
+            fs::write(samples_path, lines)?;
+        }
+        let matrix = io::BufWriter::new(File::create(path)?);
+        let row_keys = io::BufWriter::new(File::create(rows_path)?);
+        Ok(MatrixWriter::new(matrix, row_keys, npy))
+    }
+}
+
+impl<W: Write + Seek> MatrixWriter<W> {
+    pub fn new(matrix: W, row_keys: W, npy: bool) -> Self {
+        MatrixWriter {
+            matrix,
+            row_keys: Writer::new(row_keys, Layout::Flat),
+            npy,
+            site_columns: 0,
+            samples: 0,
+            column: 0,
+            rows: 0,
+        }
+    }
+
+    /// The matrix and the row-key table.
+    pub fn into_inner(self) -> (W, W) {
+        (self.matrix, self.row_keys.into_inner())
+    }
+
+    /// Write the `.npy` header for the rows written so far, at the start of the matrix.
+    fn write_npy_header(&mut self) -> anyhow::Result<()> {
+        let dict = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.rows, self.samples
+        );
+        let mut header = b"\x93NUMPY\x01\x00".to_vec();
+        header.extend_from_slice(&((NPY_HEADER_SIZE - 10) as u16).to_le_bytes());
+        header.extend_from_slice(dict.as_bytes());
+        header.resize(NPY_HEADER_SIZE - 1, b' ');
+        header.push(b'\n');
+        self.matrix.seek(SeekFrom::Start(0))?;
+        self.matrix.write_all(&header)?;
+        Ok(())
+    }
+
+    /// The row-key table, if the next value is a row key.
+    fn row_key(&mut self) -> Option<&mut Writer<W>> {
+        if self.column >= ROW_KEYS {
+            return None;
+        }
+        self.column += 1;
+        Some(&mut self.row_keys)
+    }
+
+    /// Start the cell of the next value; false for a site field that is not a row key.
+    fn cell(&mut self) -> anyhow::Result<bool> {
+        let column = self.column;
+        self.column += 1;
+        if column < self.site_columns {
+            return Ok(false);
+        }
+        if !self.npy && column > self.site_columns {
+            self.matrix.write_all(b"\t")?;
+        }
+        Ok(true)
+    }
+
+    fn write_missing(&mut self) -> anyhow::Result<()> {
+        if self.cell()? {
+            if self.npy {
+                self.matrix.write_all(&f32::NAN.to_le_bytes())?;
+            } else {
+                self.matrix.write_all(b"NA")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> RowSink for MatrixWriter<W> {
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        let is_format = |column: &Column| matches!(column.kind, ColumnKind::Format { .. });
+        self.site_columns = columns.iter().position(is_format).unwrap_or(columns.len());
+        let (site, samples) = columns.split_at(self.site_columns);
+        self.samples = samples.len();
+
+        for column in &site[..ROW_KEYS] {
+            self.row_keys.write_field(column.name.as_bytes())?;
+        }
+        self.row_keys.newline()?;
+        if self.npy {
+            self.write_npy_header()?;
+            return Ok(());
+        }
+        let names = samples.iter().filter_map(|column| {
+            match &column.kind {
+                ColumnKind::Format { sample } => Some(sample),
+                _ => None,
+            }
+        });
+        writeln!(self.matrix, "{}", names.format("\t"))?;
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.column = 0;
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        if let Some(row_keys) = self.row_key() {
+            return row_keys.write_int(value);
+        }
+        if self.cell()? {
+            if self.npy {
+                self.matrix.write_all(&(value as f32).to_le_bytes())?;
+            } else {
+                write!(self.matrix, "{}", value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        if self.cell()? {
+            if self.npy {
+                self.matrix.write_all(&value.to_le_bytes())?;
+            } else {
+                write!(self.matrix, "{}", value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        if let Some(row_keys) = self.row_key() {
+            return row_keys.write_str(value);
+        }
+        self.write_missing()
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        if let Some(row_keys) = self.row_key() {
+            return row_keys.write_null();
+        }
+        self.write_missing()
+    }
+
+    fn write_flag(&mut self, _value: bool) -> anyhow::Result<()> {
+        self.write_missing()
+    }
+
+    fn write_genotype(&mut self, encoded: &[i32], allele: usize) -> anyhow::Result<()> {
+        match dosage(encoded, allele) {
+            Some(copies) => self.write_int(copies),
+            None => self.write_missing(),
+        }
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        self.row_keys.newline()?;
+        if !self.npy {
+            self.matrix.write_all(b"\n")?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.row_keys.finish()?;
+        if self.npy {
+            self.write_npy_header()?;
+            self.matrix.seek(SeekFrom::End(0))?;
+        }
+        self.matrix.flush()?;
+        Ok(())
+    }
+}
+
+/// Copies of `allele` in a genotype, missing if any of its alleles is.
+fn dosage(encoded: &[i32], allele: usize) -> Option<i32> {
+    let alleles = trim_ints(encoded);
+    let mut copies = 0;
+    for &a in alleles {
+        match GenotypeAllele::from(a).index() {
+            Some(i) if i as usize == allele => copies += 1,
+            Some(_) => (),
+            None => return None,
+        }
+    }
+    (!alleles.is_empty()).then_some(copies)
+}
+
+/// Remove the files of a failed conversion to a matrix at `path`.
+pub(crate) fn remove(path: &Path) {
+    let (rows_path, samples_path) = companion_paths(path);
+    for path in [path, &rows_path, &samples_path] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::io::Cursor;
+
+    const RECORDS: &[&str] = &[
+        "1\t100\t.\tA\tC,G\t50\tPASS\t.\tGT\t0/1\t1|2",
+        "1\t200\t.\tT\tG\t.\t.\t.\tGT\t./.\t1/1",
+    ];
+
+    /// The matrix and row keys of `RECORDS`, as dosages.
+    fn convert(npy: bool) -> (Vec<u8>, String) {
+        let writer = testing::written(
+            &testing::vcf(RECORDS),
+            |header| plan(header, &MatrixValue::Dosage).unwrap(),
+            |_| MatrixWriter::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), npy),
+        );
+        let (matrix, row_keys) = writer.into_inner();
+        (matrix.into_inner(), String::from_utf8(row_keys.into_inner()).unwrap())
+    }
+
+    #[test]
+    fn writes_an_aligned_npy_header_with_the_final_shape() {
+        let (npy, _) = convert(true);
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (3, 2), }"));
+        assert!(header.ends_with(" \n"));
+
+        let values: Vec<f32> = npy[10 + header_len..]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(values.len(), 6);
+        assert_eq!(values[..4], [1.0, 1.0, 0.0, 1.0]);
+        assert!(values[4].is_nan());
+        assert_eq!(values[5], 2.0);
+    }
+
+    #[test]
+    fn writes_dosages_per_alt_allele_with_row_keys() {
+        let (matrix, row_keys) = convert(false);
+        assert_eq!(String::from_utf8(matrix).unwrap(), "s1\ts2\n1\t1\n0\t1\nNA\t2\n");
+        assert_eq!(row_keys, "CHROM\tPOS\tREF\tALT\n1\t100\tA\tC\n1\t100\tA\tG\n1\t200\tT\tG\n");
+    }
+}
@@ -0,0 +1,354 @@
+use std::fs::{ self, File };
+use std::io::{ self, Write };
+use std::path::Path;
+
+use crate::plan::{ Column, ColumnKind, HeaderPlan, Projection, SampleDefinition };
+use crate::sink::RowSink;
+use crate::writer::{ Layout, Writer };
+
+/// The files written into the output directory, in the order they are opened.
+pub const TABLES: [&str; 4] = ["variants.tsv", "alleles.tsv", "samples.tsv", "genotypes.tsv"];
+
+/// Where a value of the row goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Variant,
+    Allele,
+    /// A FORMAT value, and the ID of its sample if it is the first value of the sample.
+    Genotype(Option<usize>),
+}
+
+/// Writes rows as four tab-separated tables linked by surrogate keys.
+///
+/// `variants` holds a `variant_id` and the site fields and INFO tags of each VCF record, and
+/// `alleles` an `allele_id`, the `variant_id` of its record, the ALT allele and the INFO tags
+/// with a value per allele of each row; the `_REF` column of a split Number=R tag belongs to
+/// the variant. `samples` holds a `sample_id`, the name and the
+/// `##SAMPLE`/`##PEDIGREE` fields of each sample, and `genotypes` the FORMAT tags of each row
+/// and sample, keyed by `allele_id` and `sample_id`, as their values are split per ALT allele,
+/// with the `variant_id` of the record.
+/// Header metadata, if any, goes before the `variants` table.
+pub struct NormalizedWriter<W: Write> {
+    variants: Writer<W>,
+    alleles: Writer<W>,
+    samples: Writer<W>,
+    genotypes: Writer<W>,
+    sample_names: Vec<String>,
+    definitions: Vec<SampleDefinition>,
+    /// The table of each INFO column: values per ALT allele go with the allele, REF values and
+    /// the others with the variant.
+    info_routes: Vec<Route>,
+    routes: Vec<Route>,
+    /// Site and INFO columns; any after them are FORMAT values per sample.
+    site_columns: usize,
+    /// Index of the next value in the current row.
+    column: usize,
+    /// Whether the current row is the first of its record, which writes the variant.
+    first_row: bool,
+    variant_id: u64,
+    allele_id: u64,
+    allele_id_text: String,
+}
+
+impl NormalizedWriter<io::BufWriter<File>> {
+    /// Create the tables in directory `path`, creating it if needed.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        plan: &HeaderPlan,
+        definitions: Vec<SampleDefinition>,
+    ) -> anyhow::Result<Self> {
+        fs::create_dir_all(&path)?;
+        let [variants, alleles, samples, genotypes] = TABLES
+            .map(|table| File::create(path.as_ref().join(table)).map(io::BufWriter::new));
+        Ok(NormalizedWriter::new([variants?, alleles?, samples?, genotypes?], plan, definitions))
+    }
+}
+
+impl<W: Write> NormalizedWriter<W> {
+    /// Write to the given tables, in `TABLES` order, for the columns of `plan`, numbering the
+    /// samples in plan order and describing them with the fields of their `definitions`.
+    pub fn new(tables: [W; 4], plan: &HeaderPlan, definitions: Vec<SampleDefinition>) -> Self {
+        let [variants, alleles, samples, genotypes] = tables.map(|table| {
+            Writer::new(table, Layout::Flat)
+        });
+        NormalizedWriter {
+            variants,
+            alleles,
+            samples,
+            genotypes,
+            sample_names: plan.samples.clone(),
+            definitions,
+            info_routes: plan
+                .info
+                .iter()
+                .flat_map(|tag| {
+                    match tag.projection {
+                        Projection::Alt | Projection::Allele => vec![Route::Allele],
+                        Projection::RefAlt => vec![Route::Variant, Route::Allele],
+                        _ => vec![Route::Variant],
+                    }
+                })
+                .collect(),
+            routes: Vec::new(),
+            site_columns: 0,
+            column: 0,
+            first_row: true,
+            variant_id: 0,
+            allele_id: 0,
+            allele_id_text: String::new(),
+        }
+    }
+
+    /// The tables, in `TABLES` order.
+    pub fn into_inner(self) -> [W; 4] {
+        [self.variants, self.alleles, self.samples, self.genotypes].map(Writer::into_inner)
+    }
+
+    /// The table of the next value, if it is written, starting a genotype line before the first
+    /// value of each sample.
+    fn table(&mut self) -> anyhow::Result<Option<&mut Writer<W>>> {
+        let route = self.routes.get(self.column).copied().unwrap_or(Route::Genotype(None));
+        self.column += 1;
+        match route {
+            Route::Variant if self.first_row => Ok(Some(&mut self.variants)),
+            Route::Variant => Ok(None),
+            Route::Allele => Ok(Some(&mut self.alleles)),
+            Route::Genotype(Some(sample_id)) => {
+                if sample_id > 1 {
+                    self.genotypes.newline()?;
+                }
+                self.genotypes.write_field(self.variant_id.to_string().as_bytes())?;
+                self.genotypes.write_field(self.allele_id_text.as_bytes())?;
+                self.genotypes.write_field(sample_id.to_string().as_bytes())?;
+                Ok(Some(&mut self.genotypes))
+            }
+            Route::Genotype(None) => Ok(Some(&mut self.genotypes)),
+        }
+    }
+}
+
+impl<W: Write> RowSink for NormalizedWriter<W> {
+    fn metadata(&mut self, entries: &[(String, String)]) -> anyhow::Result<()> {
+        self.variants.metadata(entries)
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        let mut info = self.info_routes.iter();
+        let mut format = Vec::new();
+        let mut variant_names = vec!["variant_id"];
+        let mut allele_names = vec!["allele_id", "variant_id"];
+        self.routes = columns
+            .iter()
+            .map(|column| {
+                let route = match &column.kind {
+                    ColumnKind::Fixed if column.name == "ALT" => Route::Allele,
+                    ColumnKind::Fixed => Route::Variant,
+                    ColumnKind::Info => info.next().copied().unwrap_or(Route::Variant),
+                    ColumnKind::Format { .. } => {
+                        format.push(column);
+                        Route::Genotype(None)
+                    }
+                };
+                match route {
+                    Route::Variant => variant_names.push(column.name.as_str()),
+                    Route::Allele => allele_names.push(column.name.as_str()),
+                    Route::Genotype(_) => (),
+                }
+                route
+            })
+            .collect();
+        // FORMAT columns come grouped by sample, with the same tags in the same order for each
+        let tags = format.len() / self.sample_names.len().max(1);
+        self.site_columns = columns.len() - format.len();
+        for sample in 0..self.sample_names.len() {
+            if let Some(route) = self.routes.get_mut(self.site_columns + sample * tags) {
+                *route = Route::Genotype(Some(sample + 1));
+            }
+        }
+
+        write_line(&mut self.variants, variant_names)?;
+        write_line(&mut self.alleles, allele_names)?;
+        let tags = format[..tags].iter().map(|column| column.name.as_str());
+        let keys = ["variant_id", "allele_id", "sample_id"];
+        write_line(&mut self.genotypes, keys.into_iter().chain(tags))?;
+
+        // One column per field any sample is described with, in order of appearance
+        let mut fields: Vec<&str> = Vec::new();
+        for definition in &self.definitions {
+            for (field, _) in &definition.fields {
+                if !fields.contains(&field.as_str()) {
+                    fields.push(field);
+                }
+            }
+        }
+        write_line(&mut self.samples, ["sample_id", "name"].iter().copied().chain(fields.clone()))?;
+        for (i, sample) in self.sample_names.iter().enumerate() {
+            let definition = self.definitions.iter().find(|definition| definition.id == *sample);
+            let values = fields.iter().map(|field| {
+                definition
+                    .and_then(|definition| definition.fields.iter().find(|(f, _)| f == field))
+                    .map_or("", |(_, value)| value.as_str())
+            });
+            let sample_id = (i + 1).to_string();
+            let line = [sample_id.as_str(), sample.as_str()].into_iter().chain(values);
+            write_line(&mut self.samples, line)?;
+        }
+        self.samples.finish()
+    }
+
+    fn begin_record(&mut self) -> anyhow::Result<()> {
+        self.first_row = true;
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        if self.first_row {
+            self.variant_id += 1;
+            self.variants.write_field(self.variant_id.to_string().as_bytes())?;
+        }
+        self.allele_id += 1;
+        self.allele_id_text = self.allele_id.to_string();
+        self.column = 0;
+        self.alleles.write_field(self.allele_id_text.as_bytes())?;
+        self.alleles.write_field(self.variant_id.to_string().as_bytes())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        if let Some(table) = self.table()? {
+            table.write_int(value)?;
+        }
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        if let Some(table) = self.table()? {
+            table.write_float(value)?;
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        if let Some(table) = self.table()? {
+            table.write_str(value)?;
+        }
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        if let Some(table) = self.table()? {
+            table.write_null()?;
+        }
+        Ok(())
+    }
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()> {
+        if let Some(table) = self.table()? {
+            table.write_flag(value)?;
+        }
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        if self.first_row {
+            self.variants.newline()?;
+            self.first_row = false;
+        }
+        self.alleles.newline()?;
+        if self.column > self.site_columns {
+            self.genotypes.newline()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.variants.finish()?;
+        self.alleles.finish()?;
+        self.genotypes.finish()
+    }
+}
+
+fn write_line<'a, W: Write>(
+    writer: &mut Writer<W>,
+    fields: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Result<()> {
+    for field in fields {
+        writer.write_field(field.as_bytes())?;
+    }
+    writer.newline()
+}
+
+/// Remove the tables of a failed conversion from `path`, and the directory if left empty.
+pub(crate) fn remove(path: &Path) {
+    for table in TABLES {
+        let _ = fs::remove_file(path.join(table));
+    }
+    let _ = fs::remove_dir(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{ self, ConversionOptions };
+    use crate::plan::TagSelection;
+    use crate::testing;
+
+    #[test]
+    fn writes_a_variant_per_record_and_genotypes_per_allele() {
+        let text = testing::vcf(&[
+            "1\t100\t.\tA\tC,G\t50\tPASS\tDP=10;AF=0.25,0.5\tGT:DP\t0/1:7\t1|2:.",
+            "1\t200\t.\tT\tG\t.\t.\t.\tGT\t./.\t0/1",
+        ]);
+        let writer = testing::written(
+            &text,
+            |header| {
+                let info = TagSelection::only(["DP", "AF"]);
+                HeaderPlan::new(header, &info, &TagSelection::only(["GT", "DP"])).unwrap()
+            },
+            |plan| NormalizedWriter::new(Default::default(), plan, Vec::new()),
+        );
+        let [variants, alleles, samples, genotypes] =
+            writer.into_inner().map(|table| String::from_utf8(table).unwrap());
+        assert_eq!(
+            variants,
+            "variant_id\tCHROM\tPOS\tREF\tQUAL\tFILTER\tDP\n\
+             1\t1\t100\tA\t50\tPASS\t10\n\
+             2\t1\t200\tT\t\t\t\n"
+        );
+        assert_eq!(
+            alleles,
+            "allele_id\tvariant_id\tALT\tAF\n1\t1\tC\t0.25\n2\t1\tG\t0.5\n3\t2\tG\t\n"
+        );
+        assert_eq!(samples, "sample_id\tname\n1\ts1\n2\ts2\n");
+        assert_eq!(
+            genotypes,
+            "variant_id\tallele_id\tsample_id\tGT\tDP\n\
+             1\t1\t1\t0/1\t7\n1\t1\t2\t1|.\t\n\
+             1\t2\t1\t0/.\t7\n1\t2\t2\t.|1\t\n\
+             2\t3\t1\t./.\t\n2\t3\t2\t0/1\t\n"
+        );
+    }
+    #[test]
+    fn keeps_the_reference_value_of_number_r_info_tags_with_the_variant() {
+        let text = testing::vcf(&[
+            "1\t100\t.\tA\tC,G\t50\tPASS\tRC=5,3,2\tGT\t0/1\t1|2",
+        ]);
+        let writer = testing::written(
+            &text,
+            |header| {
+                let options = ConversionOptions::new()
+                    .info_tags(TagSelection::only(["RC"]))
+                    .format_tags(TagSelection::only(["GT"]))
+                    .genotype_table(true);
+                convert::output_plan(header, &options).unwrap()
+            },
+            |plan| NormalizedWriter::new(Default::default(), plan, Vec::new()),
+        );
+        let [variants, alleles, _, _] =
+            writer.into_inner().map(|table| String::from_utf8(table).unwrap());
+        assert_eq!(
+            variants,
+            "variant_id\tCHROM\tPOS\tREF\tQUAL\tFILTER\tRC_REF\n1\t1\t100\tA\t50\tPASS\t5\n"
+        );
+        assert_eq!(alleles, "allele_id\tvariant_id\tALT\tRC_ALT\n1\t1\tC\t3\n2\t1\tG\t2\n");
+    }
+}
@@ -0,0 +1,364 @@
+use rust_htslib::bcf;
+use rust_htslib::bcf::record::Buffer;
+use rust_htslib::bcf::Read;
+use std::collections::BTreeMap;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, Sender };
+use std::sync::Mutex;
+use std::thread;
+
+use anyhow::{ anyhow, bail };
+
+use crate::convert::{ read_next, ConversionOptions };
+use crate::decode::RecordBuffers;
+use crate::format::{ write_rows, RowFormatter };
+use crate::plan::HeaderPlan;
+use crate::progress::{ Checkpoint, CHECK_PERIOD };
+use crate::report::Tally;
+use crate::sink::RowSink;
+use crate::writer::{ Layout, Writer };
+
+/// Records decoded on the reading thread before being handed to a worker.
+const BATCH_SIZE: usize = 256;
+
+type Batch = (usize, Vec<RecordBuffers>);
+type Formatted = anyhow::Result<(usize, Vec<u8>, Vec<RecordBuffers>)>;
+type Part = (usize, anyhow::Result<PartFile>);
+
+/// Convert with `options.threads` workers formatting batches of decoded records.
+///
+/// The calling thread reads and decodes records, then writes the formatted batches back in the
+/// order they were read, so the output is the same as a sequential conversion.
+pub(crate) fn convert<W: Write>(
+    reader: &mut bcf::Reader,
+    plan: &HeaderPlan,
+    options: &ConversionOptions,
+    writer: &mut Writer<W>,
+    checkpoint: &mut Checkpoint,
+    tally: &mut Tally,
+) -> anyhow::Result<()> {
+    let (job_tx, job_rx) = mpsc::channel::<Batch>();
+    let (done_tx, done_rx) = mpsc::channel::<Formatted>();
+    let job_rx = Mutex::new(job_rx);
+    let job_rx = &job_rx;
+
+    // Moving the job sender into the scope lets workers stop on an early return
+    thread::scope(move |scope| {
+        for _ in 0..options.threads {
+            let done_tx = done_tx.clone();
+            scope.spawn(move || format_batches(plan, options.layout, job_rx, done_tx));
+        }
+        drop(done_tx);
+
+        let max_in_flight = 2 * options.threads;
+        let mut rec = reader.empty_record();
+        let mut scratch = Buffer::new();
+        let mut free: Vec<Vec<RecordBuffers>> = Vec::new();
+        let mut pending = BTreeMap::new();
+        let (mut sent, mut received, mut written) = (0, 0, 0);
+        let mut eof = false;
+
+        while !eof {
+            let mut batch = free.pop().unwrap_or_default();
+            let mut len = 0;
+            while len < BATCH_SIZE {
+                if !read_next(reader, &mut rec, options.on_error, tally)? {
+                    eof = true;
+                    break;
+                }
+                if len == batch.len() {
+                    batch.push(RecordBuffers::new(plan));
+                }
+                batch[len].decode(plan, reader.header(), &rec, &mut scratch)?;
+                // Text rows are never dropped, so every ALT allele makes one
+                let rows = batch[len].allele_count().saturating_sub(1);
+                tally.record(rec.rid(), &batch[len], rows);
+                checkpoint.record(rec.rid(), rec.pos(), rows)?;
+                len += 1;
+            }
+            batch.truncate(len);
+            if len > 0 {
+                job_tx.send((sent, batch))?;
+                sent += 1;
+            }
+
+            while received < sent {
+                let result = if sent - received >= max_in_flight || eof {
+                    done_rx.recv().map_err(|_| anyhow!("conversion worker exited early"))?
+                } else if let Ok(result) = done_rx.try_recv() {
+                    result
+                } else {
+                    break;
+                };
+                let (seq, rows, batch) = result?;
+                received += 1;
+                pending.insert(seq, rows);
+                free.push(batch);
+                while let Some(rows) = pending.remove(&written) {
+                    writer.append(&rows)?;
+                    written += 1;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+fn format_batches(
+    plan: &HeaderPlan,
+    layout: Layout,
+    jobs: &Mutex<Receiver<Batch>>,
+    done: Sender<Formatted>,
+) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let Ok((seq, batch)) = job else {
+            return;
+        };
+        let mut writer = Writer::new(Vec::new(), layout);
+        let result = batch
+            .iter()
+            .try_for_each(|rec| write_rows(plan, rec, &mut writer).map(|_| ()))
+            .map(|_| (seq, writer.into_inner(), batch));
+        if done.send(result).is_err() {
+            return;
+        }
+    }
+}
+
+/// Convert an indexed input with `options.threads` workers, each fetching whole contigs through
+/// its own `IndexedReader`.
+///
+/// Every contig is formatted into a part file of a private temporary directory, and the parts
+/// are appended to `writer` in header contig order as soon as all the ones before them are done.
+pub(crate) fn convert_by_contig<W: Write>(
+    path: &Path,
+    plan: &HeaderPlan,
+    options: &ConversionOptions,
+    writer: &mut Writer<W>,
+    checkpoint: &mut Checkpoint,
+    tally: &mut Tally,
+) -> anyhow::Result<()> {
+    let contig_count = bcf::IndexedReader::from_path(path)?.header().contig_count() as usize;
+    let dir = tempfile::Builder::new().prefix("vcf2csv-").tempdir()?;
+    let parts = dir.path();
+    let next_contig = AtomicUsize::new(0);
+    let (records, rows) = (AtomicU64::new(0), AtomicU64::new(0));
+    let stop = AtomicBool::new(false);
+    let workers = Workers {
+        next_contig: &next_contig,
+        records: &records,
+        rows: &rows,
+        stop: &stop,
+    };
+    let (done_tx, done_rx) = mpsc::channel::<Part>();
+
+    thread::scope(move |scope| {
+        let handles: Vec<_> = (0..options.threads.min(contig_count))
+            .map(|_| {
+                let done = done_tx.clone();
+                scope.spawn(move || convert_contigs(path, plan, options, parts, workers, done))
+            })
+            .collect();
+        drop(done_tx);
+        // Workers give up on their current contig as soon as this returns, even early
+        let _stop = StopOnDrop(workers.stop);
+
+        let mut pending = BTreeMap::new();
+        let mut written = 0;
+        while written < contig_count {
+            workers.advance(checkpoint, written)?;
+            let (rid, part) = match done_rx.recv_timeout(CHECK_PERIOD) {
+                Ok(done) => done,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => bail!("conversion worker exited early"),
+            };
+            pending.insert(rid, part?);
+            while let Some(part) = pending.remove(&written) {
+                io::copy(&mut File::open(&part.path)?, writer.get_mut())?;
+                written += 1;
+            }
+        }
+        for handle in handles {
+            tally.merge(handle.join().map_err(|_| anyhow!("conversion worker panicked"))?);
+        }
+        workers.advance(checkpoint, written)
+    })
+}
+
+/// State shared by the contig workers of one conversion.
+#[derive(Clone, Copy)]
+struct Workers<'a> {
+    next_contig: &'a AtomicUsize,
+    records: &'a AtomicU64,
+    rows: &'a AtomicU64,
+    /// Set once the calling thread no longer waits for parts.
+    stop: &'a AtomicBool,
+}
+
+impl Workers<'_> {
+    /// Bring `checkpoint` up to date with the workers, once the first `written` contigs are
+    /// appended to the output.
+    fn advance(&self, checkpoint: &mut Checkpoint, written: usize) -> anyhow::Result<()> {
+        // Only the contigs already appended count as converted
+        let last = written.checked_sub(1).map(|rid| rid as u32);
+        let records = self.records.load(Ordering::Relaxed);
+        let rows = self.rows.load(Ordering::Relaxed);
+        checkpoint.advance(records, rows, last)
+    }
+}
+
+struct StopOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for StopOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn convert_contigs(
+    path: &Path,
+    plan: &HeaderPlan,
+    options: &ConversionOptions,
+    parts: &Path,
+    workers: Workers,
+    done: Sender<Part>,
+) -> Tally {
+    let mut reader = match bcf::IndexedReader::from_path(path) {
+        Ok(reader) => reader,
+        Err(e) => {
+            let rid = workers.next_contig.fetch_add(1, Ordering::Relaxed);
+            let _ = done.send((rid, Err(e.into())));
+            return Tally::default();
+        }
+    };
+    let mut tally = Tally::new(plan, reader.header());
+    let contig_count = reader.header().contig_count() as usize;
+    loop {
+        let rid = workers.next_contig.fetch_add(1, Ordering::Relaxed);
+        if rid >= contig_count || workers.stop.load(Ordering::Relaxed) {
+            return tally;
+        }
+        let part = PartFile { path: parts.join(format!("{}.part", rid)) };
+        let result =
+            convert_contig(&mut reader, rid as u32, plan, options, workers, &mut tally, &part.path)
+                .map(|_| part);
+        if done.send((rid, result)).is_err() {
+            return tally;
+        }
+    }
+}
+
+fn convert_contig(
+    reader: &mut bcf::IndexedReader,
+    rid: u32,
+    plan: &HeaderPlan,
+    options: &ConversionOptions,
+    workers: Workers,
+    tally: &mut Tally,
+    path: &Path,
+) -> anyhow::Result<()> {
+    // Never through a file, or a link, that is already there
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = Writer::new(BufWriter::new(file), options.layout);
+    // htslib cannot seek to a contig without indexed records, which then has no rows
+    if reader.fetch(rid, 0, None).is_ok() {
+        let mut formatter = RowFormatter::new(plan);
+        let mut rec = reader.empty_record();
+        while !workers.stop.load(Ordering::Relaxed)
+            && read_next(reader, &mut rec, options.on_error, tally)?
+        {
+            let rows = formatter.write_record(reader.header(), &rec, &mut writer)?;
+            tally.record(Some(rid), formatter.buffers(), rows);
+            workers.records.fetch_add(1, Ordering::Relaxed);
+            workers.rows.fetch_add(rows as u64, Ordering::Relaxed);
+        }
+    }
+    writer.finish()
+}
+
+/// The rows of one contig, removed once appended or when the conversion fails.
+struct PartFile {
+    path: PathBuf,
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::convert::{ ConversionOptions, Input };
+    use crate::plan::TagSelection;
+    use crate::testing;
+    use crate::writer::Layout;
+
+    /// Records enough for several batches, every third with two ALT alleles.
+    fn records() -> Vec<String> {
+        (1..=1000)
+            .map(|pos| {
+                if pos % 3 == 0 {
+                    format!(
+                        "1\t{}\t.\tA\tC,G\t50\tPASS\tDP={};AF=0.25,0.5;RC=5,3,2\t\
+                         GT:AD:DP\t0/1:5,3,2:{}\t1|2:.:.",
+                        pos, pos, pos
+                    )
+                } else {
+                    format!("1\t{}\t.\tT\tG\t.\t.\tDP={}\tGT:AD\t0/1:4,{}\t./.:.", pos, pos, pos)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn concatenates_contigs_in_header_order() {
+        // Contig 2 comes first in the file, but second in the header
+        let text = testing::vcf(&[
+            "2\t10\t.\tG\tA\t.\t.\tDP=1\tGT\t0/1\t0/0",
+            "2\t20\t.\tC\tT,G\t.\t.\tDP=2\tGT\t1/2\t0/1",
+            "1\t5\t.\tA\tC\t.\t.\tDP=3\tGT\t0/0\t1/1",
+        ])
+        .replacen("#CHROM", "##contig=<ID=2,length=1000>\n#CHROM", 1);
+        let (_dir, path) = testing::indexed_vcf_file(&text);
+        let options = ConversionOptions::new()
+            .layout(Layout::Flat)
+            .info_tags(TagSelection::only(["DP"]))
+            .format_tags(TagSelection::only(["GT"]));
+        for threads in [1, 2] {
+            let options = options.clone().by_contig(true).threads(threads);
+            let (tsv, report) = testing::run(Input::path(&path), options).unwrap();
+            assert_eq!(
+                tsv,
+                "CHROM\tPOS\tREF\tALT\tQUAL\tFILTER\tDP\ts1_GT\ts2_GT\n\
+                 1\t5\tA\tC\t\t\t3\t0/0\t1/1\n\
+                 2\t10\tG\tA\t\t\t1\t0/1\t0/0\n\
+                 2\t20\tC\tT\t\t\t2\t1/.\t0/1\n\
+                 2\t20\tC\tG\t\t\t2\t./1\t0/.\n"
+            );
+            assert_eq!(report.rows_per_contig, [("1".to_owned(), 1), ("2".to_owned(), 3)]);
+        }
+    }
+
+    #[test]
+    fn writes_the_same_output_with_several_threads() {
+        let records = records();
+        let text = testing::vcf(&records.iter().map(String::as_str).collect::<Vec<_>>());
+        let file = testing::vcf_file(&text);
+        let convert = |threads| {
+            let options = ConversionOptions::new().layout(Layout::Flat).threads(threads);
+            testing::run(Input::path(file.path()), options).unwrap()
+        };
+        let (sequential, report) = convert(1);
+        assert_eq!(report.rows_written, 1333);
+        for threads in [2, 4] {
+            let (threaded, threaded_report) = convert(threads);
+            assert!(threaded == sequential, "{} threads change the output", threads);
+            assert_eq!(threaded_report.rows_written, report.rows_written);
+        }
+    }
+}
@@ -0,0 +1,613 @@
+use itertools::Itertools;
+use rust_htslib::bcf::header::{ HeaderView, TagLength, TagType };
+use rust_htslib::bcf::HeaderRecord;
+use std::fmt;
+
+use crate::ParseError;
+
+/// Site-level columns written before the INFO tags, in output order.
+pub const FIXED_COLUMNS: &[&str] = &["CHROM", "POS", "REF", "ALT", "QUAL", "FILTER"];
+
+/// The FORMAT tags per-sample values are derived from, with the type and number they need.
+const DEPTH_TAGS: &[(&str, TagType, TagLength)] = &[
+    ("AD", TagType::Integer, TagLength::Alleles),
+    ("DP", TagType::Integer, TagLength::Fixed(1)),
+    ("AF", TagType::Float, TagLength::AltAlleles),
+];
+
+/// Which INFO or FORMAT tags end up in the output.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TagSelection {
+    /// Every tag declared in the header, in header order.
+    #[default]
+    All,
+    /// Only the given tags, in the given order.
+    Only(Vec<String>),
+}
+
+impl TagSelection {
+    pub fn only<I, S>(tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        TagSelection::Only(tags.into_iter().map(Into::into).collect())
+    }
+
+    fn apply(&self, declared: Vec<String>) -> anyhow::Result<Vec<String>> {
+        match self {
+            TagSelection::All => Ok(declared),
+            TagSelection::Only(tags) => {
+                for tag in tags {
+                    if !declared.contains(tag) {
+                        return Err(ParseError::UndeclaredTag(tag.clone()).into());
+                    }
+                }
+                Ok(tags.clone())
+            }
+        }
+    }
+}
+
+impl fmt::Display for TagSelection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagSelection::All => f.write_str("all"),
+            TagSelection::Only(tags) => f.write_str(&tags.join(",")),
+        }
+    }
+}
+
+/// Where the values of an output column come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnKind {
+    Fixed,
+    Info,
+    Format { sample: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub kind: ColumnKind,
+    pub name: String,
+    /// The type of the values, in the terms of the header; `Flag` columns hold booleans.
+    pub value_type: TagType,
+}
+
+/// Where the values of an output column come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnSource<'a> {
+    /// A site field of the record.
+    Fixed,
+    /// An INFO or FORMAT tag.
+    Tag(&'a TagPlan),
+    /// Computed from the depth tags of the sample.
+    Derived(Derived),
+}
+
+/// A per-sample value computed from the AD, DP and AF of the call, whichever of them are
+/// selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Derived {
+    /// DP, else the sum of AD over every allele.
+    Depth,
+    /// The variant allele frequency: the AD of the ALT allele over the sum of AD, else over DP,
+    /// or the AF of the ALT allele.
+    Vaf,
+}
+
+impl Derived {
+    pub fn name(self) -> &'static str {
+        match self {
+            Derived::Depth => "depth",
+            Derived::Vaf => "VAF",
+        }
+    }
+
+    pub fn value_type(self) -> TagType {
+        match self {
+            Derived::Depth => TagType::Integer,
+            Derived::Vaf => TagType::Float,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Derived::Depth => "Read depth: DP, else the sum of AD",
+            Derived::Vaf => {
+                "Variant allele frequency: the AD of the ALT allele over the sum of AD, else \
+                    over DP, or the AF of the ALT allele"
+            }
+        }
+    }
+}
+
+/// Which of a tag's values goes into the row of a given ALT allele.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    /// The first value, for fixed and variable numbers of values.
+    First,
+    /// One value per ALT allele (`Number=A`).
+    Alt,
+    /// One value per allele, REF first (`Number=R`).
+    Allele,
+    /// The REF value and the value of the row's ALT allele, in a `_REF` and an `_ALT` column,
+    /// of a tag with one value per allele.
+    RefAlt,
+    /// A multiplicity the conversion cannot split per ALT allele.
+    Unsupported,
+}
+
+impl Projection {
+    fn info(length: TagLength) -> Self {
+        match length {
+            TagLength::Fixed(_) | TagLength::Variable => Projection::First,
+            TagLength::AltAlleles => Projection::Alt,
+            TagLength::Alleles => Projection::Allele,
+            TagLength::Genotypes => Projection::Unsupported,
+        }
+    }
+
+    fn format(length: TagLength) -> Self {
+        match length {
+            TagLength::Fixed(_) => Projection::First,
+            TagLength::AltAlleles => Projection::Alt,
+            // Number=G values are read at the same offset as Number=R ones
+            TagLength::Alleles | TagLength::Genotypes => Projection::Allele,
+            TagLength::Variable => Projection::Unsupported,
+        }
+    }
+
+    /// Indexes of the values for the `alt`-th ALT allele (0-based), one per column of the tag.
+    pub fn indices(self, alt: usize) -> anyhow::Result<impl Iterator<Item = usize>> {
+        let (first, columns) = match self {
+            Projection::First => (0, 1),
+            Projection::Alt => (alt, 1),
+            Projection::Allele => (alt + 1, 1),
+            Projection::RefAlt => (0, 2),
+            Projection::Unsupported => return Err(ParseError::UnsupportedTagLength.into()),
+        };
+        Ok([first, alt + 1].into_iter().take(columns))
+    }
+}
+
+/// An INFO or FORMAT tag, resolved against the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagPlan {
+    pub name: String,
+    /// Numeric ID of the tag in the header dictionary.
+    pub id: u32,
+    pub tag_type: TagType,
+    pub tag_length: TagLength,
+    pub projection: Projection,
+}
+
+impl TagPlan {
+    fn info(header: &HeaderView, name: String) -> anyhow::Result<Self> {
+        let (tag_type, tag_length) = header.info_type(name.as_bytes())?;
+        Ok(TagPlan {
+            id: header.name_to_id(name.as_bytes())?.0,
+            tag_type,
+            tag_length,
+            projection: Projection::info(tag_length),
+            name,
+        })
+    }
+
+    fn format(header: &HeaderView, name: String) -> anyhow::Result<Self> {
+        let (tag_type, tag_length) = header.format_type(name.as_bytes())?;
+        Ok(TagPlan {
+            id: header.name_to_id(name.as_bytes())?.0,
+            tag_type,
+            tag_length,
+            projection: Projection::format(tag_length),
+            name,
+        })
+    }
+
+    /// The names of the columns holding the tag, `_REF` and `_ALT` ones for a split tag.
+    pub fn column_names(&self) -> Vec<String> {
+        match self.projection {
+            Projection::RefAlt => vec![format!("{}_REF", self.name), format!("{}_ALT", self.name)],
+            _ => vec![self.name.clone()],
+        }
+    }
+
+    pub fn is_genotype(&self) -> bool {
+        self.name == "GT"
+    }
+
+    /// Whether each row holds several values of the tag, e.g. `Number=2` or `Number=.`.
+    pub fn is_multi_valued(&self) -> bool {
+        self.projection == Projection::First
+            && !matches!(self.tag_length, TagLength::Fixed(0) | TagLength::Fixed(1))
+    }
+}
+
+/// An INFO, FORMAT or FILTER line of the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagDefinition {
+    pub id: String,
+    /// `Number`, absent for FILTER.
+    pub number: Option<String>,
+    /// `Type`, absent for FILTER.
+    pub tag_type: Option<String>,
+    pub description: String,
+}
+
+impl TagDefinition {
+    /// From the values of a header line, looked up by key; htslib keeps the quotes around the
+    /// description.
+    fn new<'a>(value: impl Fn(&str) -> Option<&'a String>) -> Option<Self> {
+        Some(TagDefinition {
+            id: value("ID")?.clone(),
+            number: value("Number").cloned(),
+            tag_type: value("Type").cloned(),
+            description: value("Description")
+                .map_or("", |description| description.trim_matches('"'))
+                .to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContigDefinition {
+    pub name: String,
+    pub length: Option<u64>,
+}
+
+/// What the `##SAMPLE` and `##PEDIGREE` lines with its ID say about a sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleDefinition {
+    pub id: String,
+    /// The other fields of the lines, in header order, without quotes.
+    pub fields: Vec<(String, String)>,
+}
+
+/// The definitions of a VCF header, each kind in header order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderDefinitions {
+    pub info: Vec<TagDefinition>,
+    pub format: Vec<TagDefinition>,
+    pub filters: Vec<TagDefinition>,
+    pub contigs: Vec<ContigDefinition>,
+    pub samples: Vec<SampleDefinition>,
+}
+
+impl HeaderDefinitions {
+    pub fn new(header: &HeaderView) -> Self {
+        let mut definitions = HeaderDefinitions::default();
+        for record in header.header_records() {
+            match record {
+                HeaderRecord::Info { values, .. } => {
+                    definitions.info.extend(TagDefinition::new(|key| values.get(key)));
+                }
+                HeaderRecord::Format { values, .. } => {
+                    definitions.format.extend(TagDefinition::new(|key| values.get(key)));
+                }
+                HeaderRecord::Filter { values, .. } => {
+                    definitions.filters.extend(TagDefinition::new(|key| values.get(key)));
+                }
+                HeaderRecord::Contig { values, .. } => {
+                    if let Some(name) = values.get("ID") {
+                        definitions.contigs.push(ContigDefinition {
+                            name: name.clone(),
+                            length: values.get("length").and_then(|length| length.parse().ok()),
+                        });
+                    }
+                }
+                HeaderRecord::Structured { key, values } => {
+                    if let ("SAMPLE" | "PEDIGREE", Some(id)) = (key.as_str(), values.get("ID")) {
+                        let fields = values
+                            .iter()
+                            .filter(|(field, _)| !matches!(field.as_str(), "ID" | "IDX"))
+                            .map(|(field, value)| (field, value.trim_matches('"')));
+                        definitions.describe_sample(id, fields);
+                    }
+                }
+                _ => (),
+            }
+        }
+        definitions
+    }
+
+    /// Add `fields` to the definition of sample `id`, which several lines may describe.
+    fn describe_sample<'a, I>(&mut self, id: &str, fields: I)
+    where
+        I: Iterator<Item = (&'a String, &'a str)>,
+    {
+        let fields = fields.map(|(field, value)| (field.clone(), value.to_owned()));
+        match self.samples.iter_mut().find(|sample| sample.id == id) {
+            Some(sample) => sample.fields.extend(fields),
+            None => {
+                let sample = SampleDefinition { id: id.to_owned(), fields: fields.collect() };
+                self.samples.push(sample);
+            }
+        }
+    }
+}
+
+/// The meta-information lines of a VCF header as key/value pairs, `<...>` values included.
+pub fn meta_lines(header: &HeaderView) -> Vec<(String, String)> {
+    header
+        .header_records()
+        .into_iter()
+        .map(|record| {
+            match record {
+                HeaderRecord::Generic { key, value } => (key, value),
+                HeaderRecord::Filter { key, values }
+                | HeaderRecord::Info { key, values }
+                | HeaderRecord::Format { key, values }
+                | HeaderRecord::Contig { key, values }
+                | HeaderRecord::Structured { key, values } => {
+                    // IDX is added by htslib rather than read from the file
+                    let fields = values
+                        .iter()
+                        .filter(|(field, _)| field.as_str() != "IDX")
+                        .map(|(field, value)| format!("{}={}", field, value))
+                        .join(",");
+                    (key, format!("<{}>", fields))
+                }
+            }
+        })
+        .collect()
+}
+
+/// The tumor and, if any, normal sample of a somatic VCF: those named, else those of the
+/// `##tumor_sample` and `##normal_sample` lines Mutect2 writes, else the only sample as tumor.
+pub fn tumor_normal(
+    header: &HeaderView,
+    tumor: Option<&str>,
+    normal: Option<&str>,
+) -> anyhow::Result<(String, Option<String>)> {
+    let samples: Vec<String> = header
+        .samples()
+        .into_iter()
+        .map(|sample| String::from_utf8_lossy(sample).into_owned())
+        .collect();
+    let declared = |key: &str| {
+        header.header_records().into_iter().find_map(|record| {
+            match record {
+                HeaderRecord::Generic { key: k, value } if k == key => Some(value),
+                _ => None,
+            }
+        })
+    };
+    let tumor = match tumor.map(str::to_owned).or_else(|| declared("tumor_sample")) {
+        Some(tumor) => tumor,
+        None if samples.len() == 1 => samples[0].clone(),
+        None => anyhow::bail!("the tumor sample is neither given nor declared in the header"),
+    };
+    let normal = normal.map(str::to_owned).or_else(|| declared("normal_sample"));
+    for sample in std::iter::once(&tumor).chain(&normal) {
+        if !samples.contains(sample) {
+            anyhow::bail!("sample {} is not in the VCF", sample);
+        }
+    }
+    Ok((tumor, normal))
+}
+
+/// Whether a tag has one value per allele, REF first (`Number=R`).
+fn per_allele(tag: &TagPlan) -> bool {
+    tag.tag_length == TagLength::Alleles && tag.projection == Projection::Allele
+}
+
+/// A tag with one value per allele as a `_REF` column of its REF value and an `_ALT` column of
+/// the value of the row's ALT allele, both read from the one decoded tag.
+fn split(tag: TagPlan) -> TagPlan {
+    if per_allele(&tag) { TagPlan { projection: Projection::RefAlt, ..tag } } else { tag }
+}
+
+/// The output columns of a conversion, resolved once from the VCF header.
+#[derive(Debug, Clone)]
+pub struct HeaderPlan {
+    pub info: Vec<TagPlan>,
+    pub format: Vec<TagPlan>,
+    pub samples: Vec<String>,
+    /// Whether the record ID follows POS, for the formats that name rows by it.
+    pub id: bool,
+    /// The AD, DP and AF the derived columns come from, as far as the header declares them
+    /// with a number they can be read by; those not planned as FORMAT tags are decoded apart.
+    pub depth_tags: Vec<TagPlan>,
+    /// The derived columns following the FORMAT tags of each sample.
+    pub derived: Vec<Derived>,
+}
+
+impl HeaderPlan {
+    pub fn new(
+        header: &HeaderView,
+        info_selection: &TagSelection,
+        format_selection: &TagSelection,
+    ) -> anyhow::Result<Self> {
+        let definitions = HeaderDefinitions::new(header);
+        let info_tags = definitions.info.into_iter().map(|tag| tag.id).collect();
+        let format_tags: Vec<String> =
+            definitions.format.into_iter().map(|tag| tag.id).collect();
+        let depth_tags = DEPTH_TAGS
+            .iter()
+            .filter(|(name, _, _)| format_tags.iter().any(|tag| tag == name))
+            .map(|(name, _, _)| TagPlan::format(header, (*name).to_owned()))
+            .filter_ok(|tag| {
+                DEPTH_TAGS.contains(&(tag.name.as_str(), tag.tag_type, tag.tag_length))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let samples = header
+            .samples()
+            .into_iter()
+            .map(|sample| String::from_utf8_lossy(sample).into_owned())
+            .collect();
+
+        Ok(HeaderPlan {
+            info: info_selection
+                .apply(info_tags)?
+                .into_iter()
+                .map(|name| TagPlan::info(header, name))
+                .collect::<anyhow::Result<_>>()?,
+            format: format_selection
+                .apply(format_tags)?
+                .into_iter()
+                .map(|name| TagPlan::format(header, name))
+                .collect::<anyhow::Result<_>>()?,
+            samples,
+            id: false,
+            depth_tags,
+            derived: Vec::new(),
+        })
+    }
+
+    /// Plan FORMAT AD as the `AD_REF` and `AD_ALT` columns of `split_ref_alt`. An AD without
+    /// one value per allele is left out, as it gives neither.
+    pub(crate) fn split_allele_depths(&mut self) {
+        self.format = std::mem::take(&mut self.format)
+            .into_iter()
+            .filter(|tag| tag.name != "AD" || per_allele(tag))
+            .map(|tag| if tag.name == "AD" { split(tag) } else { tag })
+            .collect();
+    }
+
+    /// Plan every Number=R INFO tag as the `_REF` and `_ALT` columns of `split_ref_alt`, for
+    /// the linked tables, which keep the REF value with the site.
+    pub(crate) fn split_info_alleles(&mut self) {
+        self.info = std::mem::take(&mut self.info).into_iter().map(split).collect();
+    }
+
+    /// Plan every Number=R tag as two columns, a `_REF` column of its REF value and an `_ALT` column
+    /// of the value of the row's ALT allele, and derive a VAF column for every sample from a
+    /// Number=R AD the header declares, unless a VAF tag is planned.
+    pub fn split_ref_alt(&mut self) {
+        self.split_info_alleles();
+        self.format = std::mem::take(&mut self.format).into_iter().map(split).collect();
+        let ad = self.depth_tags.iter().any(|tag| tag.name == "AD");
+        if ad && !self.format.iter().any(|tag| tag.name == "VAF") {
+            self.derived = vec![Derived::Vaf];
+        }
+    }
+
+    /// All output columns: the fixed site fields, then INFO tags, then FORMAT tags per sample.
+    pub fn columns(&self) -> Vec<Column> {
+        self.sourced_columns().into_iter().map(|(column, _)| column).collect()
+    }
+
+    /// The output columns, each with where its values come from.
+    pub fn sourced_columns(&self) -> Vec<(Column, ColumnSource<'_>)> {
+        let id = self.id.then_some("ID");
+        let (site, fields) = FIXED_COLUMNS.split_at(2);
+        let fixed = site.iter().chain(&id).chain(fields).map(|&name| {
+            let column = Column {
+                kind: ColumnKind::Fixed,
+                name: name.to_string(),
+                value_type: match name {
+                    "POS" => TagType::Integer,
+                    "QUAL" => TagType::Float,
+                    _ => TagType::String,
+                },
+            };
+            (column, ColumnSource::Fixed)
+        });
+        let info = self.info.iter().flat_map(|tag| {
+            tag.column_names().into_iter().map(move |name| {
+                let column = Column { kind: ColumnKind::Info, name, value_type: tag.tag_type };
+                (column, ColumnSource::Tag(tag))
+            })
+        });
+        let format = self.samples.iter().flat_map(|sample| {
+            let tags = self.format.iter().flat_map(|tag| {
+                let names = tag.column_names().into_iter();
+                names.map(move |name| (name, tag.tag_type, ColumnSource::Tag(tag)))
+            });
+            let derived = self.derived.iter().map(|&derived| {
+                let name = derived.name().to_owned();
+                (name, derived.value_type(), ColumnSource::Derived(derived))
+            });
+            tags.chain(derived).map(move |(name, value_type, source)| {
+                let kind = ColumnKind::Format { sample: sample.clone() };
+                (Column { kind, name, value_type }, source)
+            })
+        });
+        fixed.chain(info).chain(format).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn projections(tags: &[TagPlan]) -> Vec<(&str, Projection)> {
+        tags.iter().map(|tag| (tag.name.as_str(), tag.projection)).collect()
+    }
+
+    #[test]
+    fn plans_every_declared_tag_by_its_number() {
+        let plan = testing::plan(&testing::vcf(&[]));
+        assert_eq!(
+            projections(&plan.info),
+            [
+                ("DP", Projection::First),
+                ("AF", Projection::Alt),
+                ("RC", Projection::Allele),
+                ("DB", Projection::First),
+            ]
+        );
+        assert_eq!(
+            projections(&plan.format),
+            [
+                ("GT", Projection::First),
+                ("AD", Projection::Allele),
+                ("DP", Projection::First),
+                ("PL", Projection::Allele),
+            ]
+        );
+        assert_eq!(plan.samples, ["s1", "s2"]);
+        assert!(plan.info.iter().chain(&plan.format).all(|tag| !tag.is_multi_valued()));
+    }
+
+    #[test]
+    fn keeps_the_order_of_selected_tags() {
+        let (header, _) = testing::read(&testing::vcf(&[]));
+        let plan = HeaderPlan::new(
+            &header,
+            &TagSelection::only(["RC", "DP"]),
+            &TagSelection::only(["AD"]),
+        )
+        .unwrap();
+        let names: Vec<String> = plan.columns().into_iter().map(|column| column.name).collect();
+        assert_eq!(
+            names,
+            ["CHROM", "POS", "REF", "ALT", "QUAL", "FILTER", "RC", "DP", "AD", "AD"]
+        );
+        assert_eq!(plan.columns()[9].kind, ColumnKind::Format { sample: "s2".to_owned() });
+    }
+
+    #[test]
+    fn rejects_undeclared_tags() {
+        let (header, _) = testing::read(&testing::vcf(&[]));
+        let error = HeaderPlan::new(&header, &TagSelection::only(["XX"]), &TagSelection::All)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "tag XX is not declared in the VCF header");
+    }
+
+    #[test]
+    fn splits_number_r_tags_but_not_number_g_ones() {
+        let mut plan = testing::plan(&testing::vcf(&[]));
+        plan.split_ref_alt();
+        let columns = plan.columns().into_iter().skip(6).take(11);
+        let names: Vec<String> = columns.map(|column| column.name).collect();
+        assert_eq!(
+            names,
+            ["DP", "AF", "RC_REF", "RC_ALT", "DB", "GT", "AD_REF", "AD_ALT", "DP", "PL", "VAF"]
+        );
+        assert_eq!(plan.derived, [Derived::Vaf]);
+        assert_eq!(projections(&plan.info)[2], ("RC", Projection::RefAlt));
+        assert_eq!(
+            projections(&plan.format),
+            [
+                ("GT", Projection::First),
+                ("AD", Projection::RefAlt),
+                ("DP", Projection::First),
+                ("PL", Projection::Allele),
+            ]
+        );
+    }
+}
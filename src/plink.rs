@@ -0,0 +1,356 @@
+use rust_htslib::bcf::record::GenotypeAllele;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+
+use crate::decode::trim_ints;
+use crate::plan::{ Column, ColumnKind, SampleDefinition };
+use crate::sink::RowSink;
+use crate::ParseError;
+
+/// Magic number and SNP-major mode flag opening a `.bed` file.
+const BED_HEADER: [u8; 3] = [0x6c, 0x1b, 0x01];
+/// `.bed` codes of a genotype, by copies of the ALT allele (A1), then missing.
+const HOM_REF: u8 = 0b11;
+const HET: u8 = 0b10;
+const HOM_ALT: u8 = 0b00;
+const MISSING: u8 = 0b01;
+
+/// How calls that are not diploid are coded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonDiploid {
+    /// Haploid calls as homozygous, as `plink --vcf` does, and higher ploidies as missing.
+    #[default]
+    Homozygous,
+    /// Any call that is not diploid as missing.
+    Missing,
+    /// Abort the conversion on the first call that is not diploid.
+    Fail,
+}
+
+impl FromStr for NonDiploid {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "homozygous" => Ok(NonDiploid::Homozygous),
+            "missing" => Ok(NonDiploid::Missing),
+            "fail" => Ok(NonDiploid::Fail),
+            _ => Err(ParseError::UnknownOption { option: "non_diploid", value: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for NonDiploid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NonDiploid::Homozygous => "homozygous",
+            NonDiploid::Missing => "missing",
+            NonDiploid::Fail => "fail",
+        })
+    }
+}
+
+/// How the calls of a multi-allelic site are coded in the biallelic variant of each ALT allele.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Multiallelic {
+    /// Other ALT alleles count as REF, so each variant holds the dosage of its own allele.
+    #[default]
+    Split,
+    /// Calls with any other ALT allele are missing.
+    Missing,
+}
+
+impl FromStr for Multiallelic {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "split" => Ok(Multiallelic::Split),
+            "missing" => Ok(Multiallelic::Missing),
+            _ => Err(ParseError::UnknownOption { option: "multiallelic", value: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for Multiallelic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Multiallelic::Split => "split",
+            Multiallelic::Missing => "missing",
+        })
+    }
+}
+
+/// The `.bed`, `.bim` and `.fam` files of the fileset at `path`, with or without an extension.
+pub fn fileset_paths(path: &Path) -> [PathBuf; 3] {
+    ["bed", "bim", "fam"].map(|extension| path.with_extension(extension))
+}
+
+/// Writes rows as a PLINK 1.9 binary fileset: one biallelic variant per ALT allele.
+///
+/// The `.bed` holds the GT of each variant in SNP-major mode, the `.bim` its CHROM, an ID of
+/// `CHROM:POS:REF:ALT`, POS, and ALT and REF as alleles A1 and A2, and the `.fam` a line per
+/// sample, with its parents from the `##PEDIGREE` lines of the header.
+pub struct PlinkWriter<W: Write> {
+    bed: W,
+    bim: W,
+    fam: W,
+    sample_names: Vec<String>,
+    definitions: Vec<SampleDefinition>,
+    non_diploid: NonDiploid,
+    multiallelic: Multiallelic,
+    /// Site columns before the first genotype.
+    site_columns: usize,
+    /// CHROM, POS, REF and ALT of the current row.
+    site: [Vec<u8>; 4],
+    /// `.bed` bytes of the current row, four samples each.
+    genotypes: Vec<u8>,
+    /// Index of the next value in the current row.
+    column: usize,
+}
+
+impl PlinkWriter<io::BufWriter<File>> {
+    /// Create the fileset at `path`, replacing any extension by those of its files.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_names: Vec<String>,
+        definitions: Vec<SampleDefinition>,
+        non_diploid: NonDiploid,
+        multiallelic: Multiallelic,
+    ) -> anyhow::Result<Self> {
+        let [bed, bim, fam] =
+            fileset_paths(path.as_ref()).map(|path| File::create(path).map(io::BufWriter::new));
+        let mut writer = PlinkWriter::new(bed?, bim?, fam?, sample_names, definitions);
+        writer.non_diploid = non_diploid;
+        writer.multiallelic = multiallelic;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> PlinkWriter<W> {
+    /// Write to the given files, with the samples in the order of `sample_names` and their
+    /// parents from `definitions`.
+    pub fn new(
+        bed: W,
+        bim: W,
+        fam: W,
+        sample_names: Vec<String>,
+        definitions: Vec<SampleDefinition>,
+    ) -> Self {
+        PlinkWriter {
+            bed,
+            bim,
+            fam,
+            sample_names,
+            definitions,
+            non_diploid: NonDiploid::default(),
+            multiallelic: Multiallelic::default(),
+            site_columns: 0,
+            site: Default::default(),
+            genotypes: Vec::new(),
+            column: 0,
+        }
+    }
+
+    /// The `.bed`, `.bim` and `.fam` files.
+    pub fn into_inner(self) -> (W, W, W) {
+        (self.bed, self.bim, self.fam)
+    }
+
+    /// Set the `.bed` code of the next value, if it is a genotype rather than a site field.
+    fn write_code(&mut self, code: u8) {
+        let column = self.column;
+        self.column += 1;
+        if let Some(sample) = column.checked_sub(self.site_columns) {
+            self.genotypes[sample / 4] |= code << (2 * (sample % 4));
+        }
+    }
+
+    /// The `.bed` code of a genotype in the variant of ALT allele `allele`.
+    fn code(&self, encoded: &[i32], allele: usize) -> anyhow::Result<u8> {
+        let alleles = trim_ints(encoded);
+        // Ploidy first, so that `fail` stops on a call that is not diploid even if it is missing
+        let scale = match (alleles.len(), self.non_diploid) {
+            (0, _) => return Ok(MISSING),
+            (2, _) => 1,
+            (1, NonDiploid::Homozygous) => 2,
+            (_, NonDiploid::Homozygous | NonDiploid::Missing) => return Ok(MISSING),
+            (ploidy, NonDiploid::Fail) => {
+                let site = self.site.iter().map(|field| String::from_utf8_lossy(field));
+                anyhow::bail!(
+                    "a call of ploidy {} at {} is not diploid",
+                    ploidy,
+                    site.collect::<Vec<_>>().join(":")
+                );
+            }
+        };
+        let mut copies = 0;
+        for &a in alleles {
+            match GenotypeAllele::from(a).index() {
+                Some(0) => (),
+                Some(i) if i as usize == allele => copies += 1,
+                Some(_) if self.multiallelic == Multiallelic::Split => (),
+                _ => return Ok(MISSING),
+            }
+        }
+        Ok([HOM_REF, HET, HOM_ALT][copies * scale])
+    }
+}
+
+impl<W: Write> RowSink for PlinkWriter<W> {
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        let is_format = |column: &Column| matches!(column.kind, ColumnKind::Format { .. });
+        self.site_columns = columns.iter().position(is_format).unwrap_or(columns.len());
+        self.genotypes = vec![0; self.sample_names.len().div_ceil(4)];
+        self.bed.write_all(&BED_HEADER)?;
+
+        // Family and individual IDs are both the sample name, as with `plink --vcf`
+        for sample in &self.sample_names {
+            let definition = self.definitions.iter().find(|definition| definition.id == *sample);
+            let parent = |field: &str| {
+                definition
+                    .and_then(|definition| definition.fields.iter().find(|(f, _)| f == field))
+                    .map_or("0", |(_, value)| value.as_str())
+            };
+            let (father, mother) = (parent("Father"), parent("Mother"));
+            writeln!(self.fam, "{0} {0} {1} {2} 0 -9", sample, father, mother)?;
+        }
+        self.fam.flush()?;
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.column = 0;
+        self.genotypes.fill(0);
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        match self.site.get_mut(self.column) {
+            Some(field) => {
+                field.clear();
+                write!(field, "{}", value)?;
+                self.column += 1;
+            }
+            None => self.write_code(MISSING),
+        }
+        Ok(())
+    }
+
+    fn write_float(&mut self, _value: f32) -> anyhow::Result<()> {
+        self.write_code(MISSING);
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        match self.site.get_mut(self.column) {
+            Some(field) => {
+                field.clear();
+                field.extend_from_slice(value);
+                self.column += 1;
+            }
+            None => self.write_code(MISSING),
+        }
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        self.write_code(MISSING);
+        Ok(())
+    }
+
+    fn write_flag(&mut self, _value: bool) -> anyhow::Result<()> {
+        self.write_code(MISSING);
+        Ok(())
+    }
+
+    fn write_genotype(&mut self, encoded: &[i32], allele: usize) -> anyhow::Result<()> {
+        let code = self.code(encoded, allele)?;
+        self.write_code(code);
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        let [chrom, pos, reference, alt] = &self.site;
+        let id = self.site.join(&b':');
+        // No genetic distance, and ALT as the counted allele A1
+        let line = [chrom, &id[..], b"0", pos, alt, reference].join(&b'\t');
+        self.bim.write_all(&line)?;
+        self.bim.write_all(b"\n")?;
+        self.bed.write_all(&self.genotypes)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.bed.flush()?;
+        self.bim.flush()?;
+        Ok(())
+    }
+}
+
+/// Remove the files of a failed conversion to the fileset at `path`.
+pub(crate) fn remove(path: &Path) {
+    for path in fileset_paths(path) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::{ self, MatrixValue };
+    use crate::testing;
+
+    /// The `.bed` and `.bim` of the VCF records `records`.
+    fn convert(records: &[&str]) -> (Vec<u8>, String) {
+        let writer = testing::written(
+            &testing::vcf(records),
+            |header| matrix::plan(header, &MatrixValue::Dosage).unwrap(),
+            |plan| {
+                let samples = plan.samples.clone();
+                PlinkWriter::new(Vec::new(), Vec::new(), Vec::new(), samples, Vec::new())
+            },
+        );
+        let (bed, bim, _) = writer.into_inner();
+        (bed, String::from_utf8(bim).unwrap())
+    }
+
+    #[test]
+    fn packs_two_bit_codes_low_bits_first() {
+        let (bed, bim) = convert(&[
+            "1\t100\t.\tA\tC\t.\t.\t.\tGT\t0/0\t0/1",
+            "1\t200\t.\tT\tG\t.\t.\t.\tGT\t1/1\t./.",
+        ]);
+        assert_eq!(bed[..3], BED_HEADER);
+        // s1 in bits 0-1 and s2 in bits 2-3, the rest of the byte left as zero padding
+        assert_eq!(bed[3..], [0b0000_1011, 0b0000_0100]);
+        assert_eq!(bim, "1\t1:100:A:C\t0\t100\tC\tA\n1\t1:200:T:G\t0\t200\tG\tT\n");
+    }
+
+    #[test]
+    fn codes_homozygous_ref_het_hom_alt_and_missing() {
+        let (bed, _) = convert(&["1\t100\t.\tA\tC,G\t.\t.\t.\tGT\t1|2\t2"]);
+        // 11 homozygous REF, 10 heterozygous, 00 homozygous ALT and 01 missing, with the other ALT
+        // counted as REF and the haploid call as homozygous
+        assert_eq!(bed[3..], [0b0000_1110, 0b0000_0010]);
+        let (bed, _) = convert(&["1\t100\t.\tA\tC\t.\t.\t.\tGT\t./1\t0/0/1"]);
+        assert_eq!(bed[3..], [0b0000_0101]);
+    }
+
+    #[test]
+    fn checks_ploidy_before_missing_alleles() {
+        let files = (Vec::new(), Vec::new(), Vec::new());
+        let mut writer = PlinkWriter::new(files.0, files.1, files.2, Vec::new(), Vec::new());
+        let missing = i32::from(GenotypeAllele::UnphasedMissing);
+        let alt = i32::from(GenotypeAllele::Unphased(1));
+        assert_eq!(writer.code(&[missing, missing], 1).unwrap(), MISSING);
+        assert_eq!(writer.code(&[missing], 1).unwrap(), MISSING);
+        assert_eq!(writer.code(&[alt], 1).unwrap(), HOM_ALT);
+        writer.non_diploid = NonDiploid::Fail;
+        assert!(writer.code(&[missing], 1).is_err());
+        assert!(writer.code(&[alt, missing, missing], 1).is_err());
+    }
+}
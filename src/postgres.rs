@@ -0,0 +1,263 @@
+use rust_htslib::bcf::header::TagType;
+use std::io::Write;
+
+use crate::plan::Column;
+use crate::sink::RowSink;
+use crate::writer::Layout;
+
+/// Signature, flags and header extension length opening a binary COPY stream.
+const BINARY_HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+/// Field count marking the end of a binary COPY stream.
+const BINARY_TRAILER: i16 = -1;
+/// The most columns a PostgreSQL table can have.
+const MAX_COLUMNS: usize = 1600;
+
+/// Writes rows as the data of a PostgreSQL `COPY ... FROM` statement, in its text or binary
+/// format, for the table `create_table` defines.
+///
+/// Neither format has a header; the columns are those of the flat layout. Flags are booleans and
+/// missing values `NULL`.
+pub struct PostgresWriter<W: Write> {
+    inner: W,
+    binary: bool,
+    /// Fields of the table, written at the start of each binary tuple.
+    fields: i16,
+    /// Whether a field has been written on the current text line.
+    started: bool,
+}
+
+impl<W: Write> PostgresWriter<W> {
+    pub fn new(inner: W, binary: bool) -> Self {
+        PostgresWriter { inner, binary, fields: 0, started: false }
+    }
+
+    /// Write a field as its binary length and bytes, or `None` as `NULL`.
+    fn write_binary(&mut self, value: Option<&[u8]>) -> anyhow::Result<()> {
+        match value {
+            Some(bytes) => {
+                self.inner.write_all(&i32::try_from(bytes.len())?.to_be_bytes())?;
+                self.inner.write_all(bytes)?;
+            }
+            None => self.inner.write_all(&(-1i32).to_be_bytes())?,
+        }
+        Ok(())
+    }
+
+    /// Write the delimiter before any field but the first of a text line.
+    fn delimit(&mut self) -> anyhow::Result<()> {
+        if self.started {
+            self.inner.write_all(b"\t")?;
+        }
+        self.started = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> RowSink for PostgresWriter<W> {
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        if columns.len() > MAX_COLUMNS {
+            anyhow::bail!(
+                "{} columns are too many for a PostgreSQL table, which holds at most {}; select \
+                 fewer tags, or write a long genotypes table with genotype_table and SQLite or \
+                 TSV output",
+                columns.len(),
+                MAX_COLUMNS
+            );
+        }
+        if self.binary {
+            self.fields = columns.len() as i16;
+            self.inner.write_all(BINARY_HEADER)?;
+        }
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        if self.binary {
+            self.inner.write_all(&self.fields.to_be_bytes())?;
+        }
+        self.started = false;
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        if self.binary {
+            return self.write_binary(Some(&value.to_be_bytes()));
+        }
+        self.delimit()?;
+        write!(self.inner, "{}", value)?;
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        if self.binary {
+            return self.write_binary(Some(&value.to_be_bytes()));
+        }
+        self.delimit()?;
+        // The spelling `real` expects for the values Rust writes as `inf` and `NaN`
+        match value {
+            f32::INFINITY => self.inner.write_all(b"Infinity")?,
+            f32::NEG_INFINITY => self.inner.write_all(b"-Infinity")?,
+            _ => write!(self.inner, "{}", value)?,
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        // Text columns only take valid UTF-8
+        let value = String::from_utf8_lossy(value);
+        if self.binary {
+            return self.write_binary(Some(value.as_bytes()));
+        }
+        self.delimit()?;
+        let mut rest = value.as_bytes();
+        while let Some(i) = rest.iter().position(|b| b"\\\t\n\r".contains(b)) {
+            self.inner.write_all(&rest[..i])?;
+            self.inner.write_all(match rest[i] {
+                b'\t' => b"\\t",
+                b'\n' => b"\\n",
+                b'\r' => b"\\r",
+                _ => b"\\\\",
+            })?;
+            rest = &rest[i + 1..];
+        }
+        self.inner.write_all(rest)?;
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        if self.binary {
+            return self.write_binary(None);
+        }
+        self.delimit()?;
+        self.inner.write_all(b"\\N")?;
+        Ok(())
+    }
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()> {
+        if self.binary {
+            return self.write_binary(Some(&[value.into()]));
+        }
+        self.delimit()?;
+        self.inner.write_all(if value { b"t" } else { b"f" })?;
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        if !self.binary {
+            self.inner.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if self.binary {
+            self.inner.write_all(&BINARY_TRAILER.to_be_bytes())?;
+        }
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// A `CREATE TABLE` statement for `table` with the columns of the COPY data, typed from the
+/// header, preceded by any `metadata` as comments.
+pub fn create_table(table: &str, columns: &[Column], metadata: &[(String, String)]) -> String {
+    let mut sql: String =
+        metadata.iter().map(|(key, value)| format!("-- ##{}={}\n", key, value)).collect();
+    let names = Layout::Flat.header_rows(columns).pop().unwrap_or_default();
+    let definitions: Vec<String> = names
+        .iter()
+        .zip(columns)
+        .map(|(name, column)| format!("    {} {}", quote(name), sql_type(column.value_type)))
+        .collect();
+    sql.push_str(&format!("CREATE TABLE {} (\n{}\n);\n", quote(table), definitions.join(",\n")));
+    sql
+}
+
+fn sql_type(value_type: TagType) -> &'static str {
+    match value_type {
+        TagType::Flag => "boolean",
+        TagType::Integer => "integer",
+        TagType::Float => "real",
+        TagType::String => "text",
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::ColumnKind;
+
+    fn columns() -> Vec<Column> {
+        let column = |kind, name: &str, value_type| Column {
+            kind,
+            name: name.to_owned(),
+            value_type,
+        };
+        vec![
+            column(ColumnKind::Fixed, "ID", TagType::String),
+            column(ColumnKind::Info, "DP", TagType::Integer),
+            column(ColumnKind::Info, "DB", TagType::Flag),
+            column(ColumnKind::Format { sample: "s\"1".to_owned() }, "AF", TagType::Float),
+        ]
+    }
+
+    /// The COPY data of one row of `columns`, with the values given and a missing AF.
+    fn written(binary: bool, id: &[u8]) -> Vec<u8> {
+        let mut writer = PostgresWriter::new(Vec::new(), binary);
+        writer.begin(&columns()).unwrap();
+        writer.begin_row().unwrap();
+        writer.write_str(id).unwrap();
+        writer.write_int(7).unwrap();
+        writer.write_flag(true).unwrap();
+        writer.write_null().unwrap();
+        writer.end_row().unwrap();
+        writer.finish().unwrap();
+        writer.inner
+    }
+
+    #[test]
+    fn escapes_text_fields() {
+        assert_eq!(written(false, b"a\\b\tc\nd\re"), b"a\\\\b\\tc\\nd\\re\t7\tt\t\\N\n");
+        // A value reading \N stays text rather than becoming NULL
+        assert_eq!(written(false, b"\\N"), b"\\\\N\t7\tt\t\\N\n");
+    }
+
+    #[test]
+    fn frames_binary_tuples_between_header_and_trailer() {
+        let data = written(true, b"rs1");
+        let (header, rest) = data.split_at(BINARY_HEADER.len());
+        assert_eq!(header, b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0");
+        let mut tuple = vec![0, 4];
+        tuple.extend_from_slice(&[0, 0, 0, 3]);
+        tuple.extend_from_slice(b"rs1");
+        tuple.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 7]);
+        tuple.extend_from_slice(&[0, 0, 0, 1, 1]);
+        tuple.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        // The trailer is a field count of -1
+        tuple.extend_from_slice(&[0xff, 0xff]);
+        assert_eq!(rest, tuple);
+    }
+
+    #[test]
+    fn defines_a_table_of_the_flat_columns() {
+        let metadata = [("fileformat".to_owned(), "VCFv4.2".to_owned())];
+        assert_eq!(
+            create_table("calls", &columns(), &metadata),
+            "-- ##fileformat=VCFv4.2\n\
+             CREATE TABLE \"calls\" (\n    \"ID\" text,\n    \"DP\" integer,\n    \
+             \"DB\" boolean,\n    \"s\"\"1_AF\" real\n);\n"
+        );
+    }
+
+    #[test]
+    fn rejects_tables_wider_than_postgresql_allows() {
+        let columns: Vec<Column> = columns().into_iter().cycle().take(MAX_COLUMNS + 1).collect();
+        let error = PostgresWriter::new(Vec::new(), false).begin(&columns).unwrap_err();
+        assert!(error.to_string().starts_with("1601 columns are too many for a PostgreSQL table"));
+        PostgresWriter::new(Vec::new(), false).begin(&columns[1..]).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use rust_htslib::bcf::header::HeaderView;
+use std::time::{ Duration, Instant };
+
+use crate::plan::HeaderDefinitions;
+use crate::source::FileOffset;
+
+/// Time between two runs of the cancellation check.
+pub(crate) const CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// Records read between two looks at the clock, as one wide record can take milliseconds.
+const CLOCK_INTERVAL: u64 = 64;
+
+/// Polled periodically during a conversion; returning an error aborts it with that error.
+pub type CancelCheck = Box<dyn FnMut() -> anyhow::Result<()> + Send>;
+
+/// Called with the state of a conversion; returning an error aborts it with that error.
+pub type ProgressCallback = Box<dyn FnMut(&Progress) -> anyhow::Result<()> + Send>;
+
+/// How far a conversion has got.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub records: u64,
+    pub rows: u64,
+    /// Contig of the last record read, empty before the first one.
+    pub contig: String,
+    /// 1-based position of the last record read.
+    pub pos: u64,
+    /// Estimated fraction of the input converted, from the compressed offset read up to in the
+    /// file, or from the contigs converted when converting by contig. Unknown for stdin, or
+    /// by contig when the header leaves out a contig length.
+    pub fraction: Option<f64>,
+}
+
+/// The periodic checks and progress reports of a conversion, run from the thread reading the
+/// input.
+#[derive(Default)]
+pub(crate) struct Checkpoint {
+    cancel: Option<CancelCheck>,
+    progress: Option<(u64, ProgressCallback)>,
+    contigs: Vec<String>,
+    /// Length of each contig, when the header declares it.
+    lengths: Vec<Option<u64>>,
+    offset: Option<FileOffset>,
+    records: u64,
+    rows: u64,
+    rid: Option<u32>,
+    pos: u64,
+    /// Whether all of contig `rid` is converted, rather than up to `pos`.
+    contig_done: bool,
+    reported: u64,
+    checked: Option<Instant>,
+}
+
+impl Checkpoint {
+    pub(crate) fn set_cancel(&mut self, check: CancelCheck) {
+        self.cancel = Some(check);
+    }
+
+    /// Report progress every `every` records.
+    pub(crate) fn set_progress(&mut self, every: u64, callback: ProgressCallback) {
+        self.progress = Some((every.max(1), callback));
+    }
+
+    /// Whether progress is reported, which is what the offset read up to in a file is for.
+    pub(crate) fn reports_progress(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    /// Take the contig names and lengths of the input being converted, and how far it is read
+    /// when it is a file read through.
+    pub(crate) fn start(&mut self, header: &HeaderView, offset: Option<FileOffset>) {
+        let count = header.contig_count() as usize;
+        self.contigs = (0..count)
+            .map(|rid| {
+                header
+                    .rid2name(rid as u32)
+                    .map_or_else(|_| String::new(), |name| String::from_utf8_lossy(name).into())
+            })
+            .collect();
+
+        self.lengths = vec![None; count];
+        for contig in HeaderDefinitions::new(header).contigs {
+            if let Ok(rid) = header.name2rid(contig.name.as_bytes()) {
+                if let Some(length) = self.lengths.get_mut(rid as usize) {
+                    *length = contig.length;
+                }
+            }
+        }
+        self.offset = offset;
+    }
+
+    /// Count one record read and the rows it makes, running the checks when they are due.
+    pub(crate) fn record(&mut self, rid: Option<u32>, pos: i64, rows: usize) -> anyhow::Result<()> {
+        self.records += 1;
+        self.rows += rows as u64;
+        self.rid = rid;
+        self.pos = (pos + 1) as u64;
+        self.contig_done = false;
+        if self.records.is_multiple_of(CLOCK_INTERVAL)
+            && self.checked.is_none_or(|checked| checked.elapsed() >= CHECK_PERIOD)
+        {
+            self.check()?;
+        }
+        self.report_due()
+    }
+
+    /// Catch up with records read on other threads, contig `rid` and those before it being
+    /// converted.
+    pub(crate) fn advance(
+        &mut self,
+        records: u64,
+        rows: u64,
+        rid: Option<u32>,
+    ) -> anyhow::Result<()> {
+        self.records = records;
+        self.rows = rows;
+        self.rid = rid;
+        self.pos = rid.and_then(|rid| self.contig_length(rid)).unwrap_or(0);
+        self.contig_done = true;
+        self.check()?;
+        self.report_due()
+    }
+
+    /// Run the cancellation check now, for threads waiting on workers rather than reading.
+    pub(crate) fn check(&mut self) -> anyhow::Result<()> {
+        self.checked = Some(Instant::now());
+        if let Some(cancel) = &mut self.cancel {
+            cancel()?;
+        }
+        Ok(())
+    }
+
+    /// Send a last report once the whole input is converted.
+    pub(crate) fn finish(&mut self) -> anyhow::Result<()> {
+        if self.progress.is_some() {
+            let mut progress = self.progress();
+            progress.fraction = progress.fraction.map(|_| 1.0);
+            self.report(&progress)?;
+        }
+        Ok(())
+    }
+
+    /// The length of contig `rid`, when the header declares it.
+    fn contig_length(&self, rid: u32) -> Option<u64> {
+        *self.lengths.get(rid as usize)?
+    }
+
+    /// The share of the length of all contigs that contig `rid` and those before it take,
+    /// when the header declares every length.
+    fn contigs_fraction(&self, rid: u32) -> Option<f64> {
+        let total = self.lengths.iter().copied().sum::<Option<u64>>()?;
+        let done: u64 = self.lengths.get(..=rid as usize)?.iter().flatten().sum();
+        (total > 0).then(|| done as f64 / total as f64)
+    }
+
+    fn report_due(&mut self) -> anyhow::Result<()> {
+        match &self.progress {
+            Some((every, _)) if self.records >= self.reported + every => {
+                let progress = self.progress();
+                self.report(&progress)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn report(&mut self, progress: &Progress) -> anyhow::Result<()> {
+        self.reported = self.records;
+        match &mut self.progress {
+            Some((_, callback)) => callback(progress),
+            None => Ok(()),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        let contig = self.rid.and_then(|rid| self.contigs.get(rid as usize));
+        let fraction = if self.contig_done {
+            self.rid.and_then(|rid| self.contigs_fraction(rid))
+        } else {
+            self.offset.as_ref().and_then(FileOffset::fraction)
+        };
+        Progress {
+            records: self.records,
+            rows: self.rows,
+            contig: contig.cloned().unwrap_or_default(),
+            pos: self.pos,
+            fraction,
+        }
+    }
+}
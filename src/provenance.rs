@@ -0,0 +1,220 @@
+use sha2::{ Digest, Sha256 };
+use std::fs::{ self, File };
+use std::io::{ self, Read };
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+use std::time::SystemTime;
+
+use crate::convert::{ ConversionOptions, Input };
+
+/// How a table was produced: by which version, with which options and from which bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub version: &'static str,
+    /// Every conversion option, by name.
+    pub options: Vec<(String, String)>,
+    /// The input path, `-` for stdin or `<stream>` for any other byte source.
+    pub input: String,
+    /// Size of the input in bytes.
+    pub input_size: Option<u64>,
+    /// SHA-256 of the input, in lowercase hex, when asked for with `checksum`.
+    pub input_sha256: Option<String>,
+    /// When the conversion started, in RFC 3339 format.
+    pub started: String,
+}
+
+impl Provenance {
+    /// Describe converting `input`, which is checksummed right away if it is a file.
+    ///
+    /// Streams are measured and checksummed as htslib reads them instead, through the returned
+    /// input, until `finish`.
+    pub(crate) fn new(
+        input: Input,
+        options: &ConversionOptions,
+    ) -> anyhow::Result<(Self, Input, Option<InputDigest>)> {
+        let started = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        let mut provenance = Provenance {
+            version: env!("CARGO_PKG_VERSION"),
+            options: option_entries(options),
+            input: String::new(),
+            input_size: None,
+            input_sha256: None,
+            started,
+        };
+        let (input, digest) = match input {
+            Input::Path(path) => {
+                provenance.input_size = Some(fs::metadata(&path)?.len());
+                if options.checksum {
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut File::open(&path)?, &mut hasher)?;
+                    provenance.input_sha256 = Some(hex(&hasher.finalize()));
+                }
+                provenance.input = path.display().to_string();
+                (Input::Path(path), None)
+            }
+            Input::Stdin => {
+                provenance.input = "-".to_owned();
+                digesting(io::stdin(), options.checksum)
+            }
+            Input::Reader(reader) => {
+                provenance.input = "<stream>".to_owned();
+                digesting(reader, options.checksum)
+            }
+        };
+        Ok((provenance, input, digest))
+    }
+
+    /// Fill in the size and checksum of a streamed input once it has been read.
+    pub(crate) fn finish(&mut self, digest: Option<InputDigest>) {
+        if let Some(digest) = digest {
+            let state = digest.lock().expect("input digest poisoned");
+            self.input_size = Some(state.size);
+            self.input_sha256 = state.hasher.clone().map(|hasher| hex(&hasher.finalize()));
+        }
+    }
+
+    /// The provenance as header key/value pairs, leaving out what is not known.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let options = self.options.iter().map(|(name, value)| format!("{}={}", name, value));
+        let mut entries = vec![
+            ("vcf2csvVersion".to_owned(), self.version.to_owned()),
+            ("vcf2csvOptions".to_owned(), options.collect::<Vec<_>>().join(" ")),
+            ("vcf2csvInput".to_owned(), self.input.clone()),
+        ];
+        if let Some(size) = self.input_size {
+            entries.push(("vcf2csvInputSize".to_owned(), size.to_string()));
+        }
+        if let Some(sha256) = &self.input_sha256 {
+            entries.push(("vcf2csvInputSHA256".to_owned(), sha256.clone()));
+        }
+        entries.push(("vcf2csvDate".to_owned(), self.started.clone()));
+        entries
+    }
+}
+
+fn option_entries(options: &ConversionOptions) -> Vec<(String, String)> {
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref().map_or_else(|| "none".to_owned(), |path| path.display().to_string())
+    };
+    [
+        ("format", options.format.to_string()),
+        ("layout", options.layout.to_string()),
+        ("info_tags", options.info_tags.to_string()),
+        ("format_tags", options.format_tags.to_string()),
+        ("on_error", options.on_error.to_string()),
+        ("threads", options.threads.to_string()),
+        ("by_contig", options.by_contig.to_string()),
+        ("genotype_table", options.genotype_table.to_string()),
+        ("ref_alt_columns", options.ref_alt_columns.to_string()),
+        ("sheet_per_contig", options.sheet_per_contig.to_string()),
+        ("matrix_value", options.matrix_value.to_string()),
+        ("non_diploid", options.non_diploid.to_string()),
+        ("multiallelic", options.multiallelic.to_string()),
+        ("tumor_sample", options.tumor_sample.clone().unwrap_or_else(|| "default".to_owned())),
+        ("normal_sample", options.normal_sample.clone().unwrap_or_else(|| "default".to_owned())),
+        ("bed_name", options.bed_name.to_string()),
+        ("metadata", options.metadata.to_string()),
+        ("provenance", options.provenance.to_string()),
+        ("checksum", options.checksum.to_string()),
+        ("schema", path(&options.schema)),
+        ("create_table", path(&options.create_table)),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_owned(), value))
+    .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Default)]
+pub(crate) struct DigestState {
+    size: u64,
+    hasher: Option<Sha256>,
+}
+
+/// Size and checksum of a streamed input, updated by the thread feeding it to htslib.
+pub(crate) type InputDigest = Arc<Mutex<DigestState>>;
+
+fn digesting<R: Read + Send + 'static>(reader: R, checksum: bool) -> (Input, Option<InputDigest>) {
+    let hasher = checksum.then(Sha256::new);
+    let digest = Arc::new(Mutex::new(DigestState { size: 0, hasher }));
+    let input = Input::reader(Digesting { inner: reader, digest: digest.clone() });
+    (input, Some(digest))
+}
+
+struct Digesting<R> {
+    inner: R,
+    digest: InputDigest,
+}
+
+impl<R: Read> Read for Digesting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut state = self.digest.lock().expect("input digest poisoned");
+        state.size += n as u64;
+        if let Some(hasher) = &mut state.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of `abc`.
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn read_through(input: Input) {
+        match input {
+            Input::Reader(mut reader) => io::copy(&mut reader, &mut io::sink()).map(drop).unwrap(),
+            _ => panic!("a streamed input stays a stream"),
+        }
+    }
+
+    #[test]
+    fn checksums_a_stream_as_it_is_read() {
+        let options = ConversionOptions::new().checksum(true);
+        let input = Input::reader(io::Cursor::new(b"abc".to_vec()));
+        let (mut provenance, input, digest) = Provenance::new(input, &options).unwrap();
+        assert_eq!(provenance.input, "<stream>");
+        read_through(input);
+        provenance.finish(digest);
+        assert_eq!(provenance.input_size, Some(3));
+        assert_eq!(provenance.input_sha256.as_deref(), Some(ABC_SHA256));
+    }
+
+    #[test]
+    fn checksums_a_file_only_when_asked() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "abc").unwrap();
+        for checksum in [false, true] {
+            let options = ConversionOptions::new().checksum(checksum);
+            let input = Input::path(file.path());
+            let (provenance, _, digest) = Provenance::new(input, &options).unwrap();
+            assert!(digest.is_none());
+            assert_eq!(provenance.input_size, Some(3));
+            assert_eq!(provenance.input_sha256.as_deref(), checksum.then_some(ABC_SHA256));
+        }
+    }
+
+    #[test]
+    fn leaves_out_entries_not_known() {
+        let input = Input::reader(io::Cursor::new(b"abc".to_vec()));
+        let (mut provenance, input, digest) =
+            Provenance::new(input, &ConversionOptions::new()).unwrap();
+        read_through(input);
+        provenance.finish(digest);
+        let entries = provenance.entries();
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            ["vcf2csvVersion", "vcf2csvOptions", "vcf2csvInput", "vcf2csvInputSize", "vcf2csvDate"]
+        );
+        assert!(entries[1].1.starts_with("format=tsv layout=grouped "));
+        assert!(entries[1].1.contains(" checksum=false "));
+    }
+}
@@ -0,0 +1,441 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{ PyBytes, PyDict };
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+
+use crate::plan::TagDefinition;
+use crate::{ ConversionOptions, ConversionReport, Converter, Input, Output, Progress, Provenance };
+use crate::TagSelection;
+
+#[pyfunction]
+#[pyo3(
+    signature = (
+        vcf_path,
+        csv_path,
+        *,
+        info_tags = None,
+        format_tags = None,
+        format = "tsv",
+        layout = "grouped",
+        on_error = "fail",
+        threads = 1,
+        by_contig = false,
+        genotype_table = false,
+        ref_alt_columns = false,
+        sheet_per_contig = false,
+        matrix_value = "dosage",
+        non_diploid = "homozygous",
+        multiallelic = "split",
+        tumor_sample = None,
+        normal_sample = None,
+        bed_name = "variant",
+        metadata = false,
+        provenance = false,
+        checksum = false,
+        progress = None,
+        progress_every = 100_000,
+        schema = None,
+        create_table = None,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn to_txt(
+    py: Python,
+    vcf_path: &PyAny,
+    csv_path: &PyAny,
+    info_tags: Option<Vec<String>>,
+    format_tags: Option<Vec<String>>,
+    format: &str,
+    layout: &str,
+    on_error: &str,
+    threads: usize,
+    by_contig: bool,
+    genotype_table: bool,
+    ref_alt_columns: bool,
+    sheet_per_contig: bool,
+    matrix_value: &str,
+    non_diploid: &str,
+    multiallelic: &str,
+    tumor_sample: Option<String>,
+    normal_sample: Option<String>,
+    bed_name: &str,
+    metadata: bool,
+    provenance: bool,
+    checksum: bool,
+    progress: Option<PyObject>,
+    progress_every: u64,
+    schema: Option<&PyAny>,
+    create_table: Option<&PyAny>
+) -> anyhow::Result<Report> {
+    let mut options = ConversionOptions::new()
+        .format(format.parse()?)
+        .layout(layout.parse()?)
+        .info_tags(info_tags.map_or(TagSelection::All, TagSelection::Only))
+        .format_tags(format_tags.map_or(TagSelection::All, TagSelection::Only))
+        .on_error(on_error.parse()?)
+        .threads(threads)
+        .by_contig(by_contig)
+        .genotype_table(genotype_table)
+        .ref_alt_columns(ref_alt_columns)
+        .sheet_per_contig(sheet_per_contig)
+        .matrix_value(matrix_value.parse()?)
+        .non_diploid(non_diploid.parse()?)
+        .multiallelic(multiallelic.parse()?)
+        .bed_name(bed_name.parse()?)
+        .metadata(metadata)
+        .provenance(provenance)
+        .checksum(checksum);
+    options.tumor_sample = tumor_sample;
+    options.normal_sample = normal_sample;
+    if let Some(schema) = schema {
+        options.schema = companion_path(schema, csv_path, "schema", ".schema.json")?;
+    }
+    if let Some(create_table) = create_table {
+        options.create_table = companion_path(create_table, csv_path, "create_table", ".sql")?;
+    }
+
+    let file_error = FileError::default();
+    let converter = Converter::new(input(vcf_path, &file_error)?, output(csv_path, &file_error)?)
+        .with_options(options)
+        // Signals are only delivered to Python code, so a pending Ctrl-C surfaces as the
+        // KeyboardInterrupt raised by `check_signals`
+        .with_cancel_check(|| Python::with_gil(|py| py.check_signals()).map_err(Into::into));
+    let converter = match progress {
+        Some(callback) => {
+            converter.with_progress(progress_every, move |progress| {
+                Python::with_gil(|py| {
+                    callback.call1(py, (progress_dict(py, progress)?,))?;
+                    Ok(())
+                })
+            })
+        }
+        None => converter,
+    };
+    let result = py.allow_threads(|| converter.run());
+    // The exception raised by a file object beats the I/O error it caused
+    if let Some(e) = file_error.lock().unwrap().take() {
+        return Err(e.into());
+    }
+    Ok(Report::new(py, result?)?)
+}
+
+/// Describe the header of a VCF/BCF input and the columns `to_txt` would write for it, without
+/// reading any record.
+#[pyfunction]
+#[pyo3(
+    signature = (
+        vcf_path,
+        *,
+        info_tags = None,
+        format_tags = None,
+        layout = "grouped",
+        ref_alt_columns = false,
+    )
+)]
+pub fn inspect(
+    py: Python,
+    vcf_path: &PyAny,
+    info_tags: Option<Vec<String>>,
+    format_tags: Option<Vec<String>>,
+    layout: &str,
+    ref_alt_columns: bool,
+) -> anyhow::Result<PyObject> {
+    let options = ConversionOptions::new()
+        .layout(layout.parse()?)
+        .info_tags(info_tags.map_or(TagSelection::All, TagSelection::Only))
+        .format_tags(format_tags.map_or(TagSelection::All, TagSelection::Only))
+        .ref_alt_columns(ref_alt_columns);
+
+    let file_error = FileError::default();
+    let input = input(vcf_path, &file_error)?;
+    let result = py.allow_threads(|| crate::inspect::inspect(input, &options));
+    if let Some(e) = file_error.lock().unwrap().take() {
+        return Err(e.into());
+    }
+    let inspection = result?;
+
+    let contigs = PyDict::new(py);
+    for contig in &inspection.definitions.contigs {
+        contigs.set_item(&contig.name, contig.length)?;
+    }
+    let definitions = |tags: &[TagDefinition]| -> PyResult<Vec<&PyDict>> {
+        tags.iter()
+            .map(|tag| {
+                let dict = PyDict::new(py);
+                dict.set_item("id", &tag.id)?;
+                dict.set_item("number", &tag.number)?;
+                dict.set_item("type", &tag.tag_type)?;
+                dict.set_item("description", &tag.description)?;
+                Ok(dict)
+            })
+            .collect()
+    };
+
+    let dict = PyDict::new(py);
+    dict.set_item("samples", &inspection.samples)?;
+    dict.set_item("contigs", contigs)?;
+    dict.set_item("info", definitions(&inspection.definitions.info)?)?;
+    dict.set_item("format", definitions(&inspection.definitions.format)?)?;
+    dict.set_item("filters", definitions(&inspection.definitions.filters)?)?;
+    dict.set_item("columns", inspection.header_rows.last())?;
+    dict.set_item("header", &inspection.header_rows)?;
+    Ok(dict.into())
+}
+
+/// The first exception raised by a Python file object used by a conversion.
+type FileError = Arc<Mutex<Option<PyErr>>>;
+
+/// `True` for a file named after the output file with `suffix`, else its own path.
+fn companion_path(
+    value: &PyAny,
+    csv_path: &PyAny,
+    name: &str,
+    suffix: &str,
+) -> PyResult<Option<PathBuf>> {
+    if let Ok(enabled) = value.extract::<bool>() {
+        let output = csv_path.extract::<PathBuf>().ok().filter(|path| path != Path::new("-"));
+        return match (enabled, output) {
+            (false, _) => Ok(None),
+            (true, Some(output)) => {
+                let mut path = output.into_os_string();
+                path.push(suffix);
+                Ok(Some(path.into()))
+            }
+            (true, None) => {
+                let message = format!("{}=True needs an output file; pass a path", name);
+                Err(PyValueError::new_err(message))
+            }
+        };
+    }
+    value.extract().map(Some)
+}
+
+/// A path, `-` for stdin, a `bytes` buffer or a binary file object.
+fn input(source: &PyAny, file_error: &FileError) -> PyResult<Input> {
+    if let Ok(bytes) = source.downcast::<PyBytes>() {
+        return Ok(Input::reader(PyBuffer { bytes: bytes.into(), position: 0 }));
+    }
+    if source.hasattr("read")? {
+        return Ok(Input::reader(PyFile::new(source, file_error)));
+    }
+    let path: PathBuf = source.extract()?;
+    Ok(if path == Path::new("-") { Input::Stdin } else { Input::Path(path) })
+}
+
+/// A path, `-` for stdout or a binary file object.
+fn output(sink: &PyAny, file_error: &FileError) -> PyResult<Output> {
+    if sink.hasattr("write")? {
+        return Ok(Output::writer(PyFile::new(sink, file_error)));
+    }
+    let path: PathBuf = sink.extract()?;
+    Ok(if path == Path::new("-") { Output::stdout() } else { Output::Path(path) })
+}
+
+/// A `bytes` buffer, copied into the pipe to htslib a chunk at a time as it is read.
+struct PyBuffer {
+    bytes: Py<PyBytes>,
+    position: usize,
+}
+
+impl io::Read for PyBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Python::with_gil(|py| {
+            let data = &self.bytes.as_ref(py).as_bytes()[self.position..];
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            self.position += len;
+            Ok(len)
+        })
+    }
+}
+
+/// A Python binary file object read or written by the conversion.
+struct PyFile {
+    file: PyObject,
+    error: FileError,
+}
+
+impl PyFile {
+    fn new(file: &PyAny, error: &FileError) -> Self {
+        PyFile { file: file.into(), error: error.clone() }
+    }
+
+    /// Call into the file object, keeping the exception it raises to re-raise it once the
+    /// conversion is over.
+    fn call<T>(&self, f: impl FnOnce(&PyAny) -> PyResult<T>) -> io::Result<T> {
+        Python::with_gil(|py| {
+            f(self.file.as_ref(py)).map_err(|e| {
+                let message = e.to_string();
+                self.error.lock().unwrap().get_or_insert(e);
+                io::Error::other(message)
+            })
+        })
+    }
+}
+
+impl io::Read for PyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.call(|file| {
+            let data = file.call_method1("read", (buf.len(),))?;
+            let data: &[u8] = data.downcast::<PyBytes>()?.as_bytes();
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        })
+    }
+}
+
+impl io::Write for PyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.call(|file| {
+            let written = file.call_method1("write", (PyBytes::new(file.py(), buf),))?;
+            // Raw files may write less than asked, buffered ones return the full length or None
+            Ok(written.extract::<Option<usize>>()?.unwrap_or(buf.len()))
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.call(|file| {
+            if file.hasattr("flush")? {
+                file.call_method0("flush")?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// What `to_txt` read and wrote.
+#[pyclass(name = "ConversionReport", get_all, frozen)]
+pub struct Report {
+    records_read: u64,
+    rows_written: u64,
+    sites_without_alt: u64,
+    records_skipped: u64,
+    /// Contig name to number of rows, in header order.
+    rows_per_contig: Py<PyDict>,
+    unseen_info_tags: Vec<String>,
+    unseen_format_tags: Vec<String>,
+    /// Wall time in seconds.
+    elapsed: f64,
+    /// Version, options, input and checksum, when converting with `provenance=True`.
+    provenance: Option<Py<PyDict>>,
+}
+
+impl Report {
+    fn new(py: Python, report: ConversionReport) -> PyResult<Self> {
+        let rows_per_contig = PyDict::new(py);
+        for (contig, rows) in report.rows_per_contig {
+            rows_per_contig.set_item(contig, rows)?;
+        }
+        let provenance = report.provenance.map(|provenance| provenance_dict(py, &provenance));
+        Ok(Report {
+            records_read: report.records_read,
+            rows_written: report.rows_written,
+            sites_without_alt: report.sites_without_alt,
+            records_skipped: report.records_skipped,
+            rows_per_contig: rows_per_contig.into(),
+            unseen_info_tags: report.unseen_info_tags,
+            unseen_format_tags: report.unseen_format_tags,
+            elapsed: report.elapsed.as_secs_f64(),
+            provenance: provenance.transpose()?.map(Into::into),
+        })
+    }
+}
+
+#[pymethods]
+impl Report {
+    fn __repr__(&self) -> String {
+        format!(
+            "ConversionReport(records_read={}, rows_written={}, sites_without_alt={}, \
+             records_skipped={}, elapsed={:.3})",
+            self.records_read,
+            self.rows_written,
+            self.sites_without_alt,
+            self.records_skipped,
+            self.elapsed
+        )
+    }
+}
+
+/// The argument of the `progress` callback of `to_txt`.
+fn progress_dict<'py>(py: Python<'py>, progress: &Progress) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("records", progress.records)?;
+    dict.set_item("rows", progress.rows)?;
+    dict.set_item("contig", &progress.contig)?;
+    dict.set_item("pos", progress.pos)?;
+    dict.set_item("fraction", progress.fraction)?;
+    Ok(dict)
+}
+
+fn provenance_dict<'py>(py: Python<'py>, provenance: &Provenance) -> PyResult<&'py PyDict> {
+    let options = PyDict::new(py);
+    for (name, value) in &provenance.options {
+        options.set_item(name, value)?;
+    }
+    let dict = PyDict::new(py);
+    dict.set_item("version", provenance.version)?;
+    dict.set_item("options", options)?;
+    dict.set_item("input", &provenance.input)?;
+    dict.set_item("input_size", provenance.input_size)?;
+    dict.set_item("input_sha256", &provenance.input_sha256)?;
+    dict.set_item("started", &provenance.started)?;
+    Ok(dict)
+}
+
+/// A Python module implemented in Rust.
+#[pymodule]
+fn vcf2csv(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(to_txt, m)?)?;
+    m.add_function(wrap_pyfunction!(inspect, m)?)?;
+    m.add_class::<Report>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn converts_bytes_and_file_objects_into_a_file_object() {
+        let text = testing::vcf(&[
+            "1\t100\t.\tA\tC,G\t50\tPASS\tDP=10;AF=0.25,0.5\tGT:DP\t0/1:7\t1|2:.",
+            "1\t200\t.\tT\tG\t.\t.\t.\tGT\t./.\t0/1",
+        ]);
+        let file = testing::vcf_file(&text);
+        let (expected, _) = testing::run(Input::path(file.path()), ConversionOptions::new()).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| -> PyResult<()> {
+            let to_txt = wrap_pyfunction!(to_txt, py)?;
+            let io = py.import("io")?;
+            let bytes = PyBytes::new(py, text.as_bytes());
+            for vcf in [bytes, io.call_method1("BytesIO", (bytes,))?] {
+                let csv = io.call_method0("BytesIO")?;
+                let report = to_txt.call1((vcf, csv))?;
+                assert_eq!(report.getattr("rows_written")?.extract::<u64>()?, 3);
+                let written: Vec<u8> = csv.call_method0("getvalue")?.extract()?;
+                assert_eq!(String::from_utf8(written).unwrap(), expected);
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn reraises_what_the_file_object_raised() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| -> PyResult<()> {
+            let to_txt = wrap_pyfunction!(to_txt, py)?;
+            let io = py.import("io")?;
+            let vcf = io.call_method0("BytesIO")?;
+            vcf.call_method0("close")?;
+            let error = to_txt.call1((vcf, io.call_method0("BytesIO")?)).unwrap_err();
+            assert!(error.is_instance_of::<pyo3::exceptions::PyValueError>(py));
+            Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use rust_htslib::bcf::header::HeaderView;
+use std::time::Duration;
+
+use crate::decode::RecordBuffers;
+use crate::plan::{ HeaderPlan, TagPlan };
+use crate::provenance::Provenance;
+
+/// What a finished conversion read and wrote.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionReport {
+    pub records_read: u64,
+    pub rows_written: u64,
+    /// Records read but left out of the output for having no ALT allele.
+    pub sites_without_alt: u64,
+    /// Unreadable records left out under `ErrorPolicy::Skip`.
+    pub records_skipped: u64,
+    /// Rows written for each contig with any, in header order.
+    pub rows_per_contig: Vec<(String, u64)>,
+    /// Converted INFO tags no record has a value for.
+    pub unseen_info_tags: Vec<String>,
+    /// Converted FORMAT tags no record has a value for.
+    pub unseen_format_tags: Vec<String>,
+    pub elapsed: Duration,
+    /// How the table was produced, when `ConversionOptions::provenance` is set.
+    pub provenance: Option<Provenance>,
+}
+
+/// Counts gathered while converting, merged across threads converting separate records.
+#[derive(Debug, Default)]
+pub(crate) struct Tally {
+    records: u64,
+    rows: u64,
+    without_alt: u64,
+    pub(crate) skipped: u64,
+    /// Rows written, indexed by contig ID.
+    contig_rows: Vec<u64>,
+    info_seen: Vec<bool>,
+    format_seen: Vec<bool>,
+}
+
+impl Tally {
+    pub(crate) fn new(plan: &HeaderPlan, header: &HeaderView) -> Self {
+        Tally {
+            contig_rows: vec![0; header.contig_count() as usize],
+            info_seen: vec![false; plan.info.len()],
+            format_seen: vec![false; plan.format.len()],
+            ..Default::default()
+        }
+    }
+
+    /// Count one record of contig `rid`, once decoded, and the `rows` written for it.
+    pub(crate) fn record(&mut self, rid: Option<u32>, rec: &RecordBuffers, rows: usize) {
+        let rows = rows as u64;
+        self.records += 1;
+        self.rows += rows;
+        if rec.allele_count() < 2 {
+            self.without_alt += 1;
+        }
+        if let Some(rid) = rid.map(|rid| rid as usize) {
+            // htslib adds contigs missing from the header as it reads them
+            if rid >= self.contig_rows.len() {
+                self.contig_rows.resize(rid + 1, 0);
+            }
+            self.contig_rows[rid] += rows;
+        }
+        for (seen, buffer) in self.info_seen.iter_mut().zip(&rec.info) {
+            *seen |= buffer.is_present();
+        }
+        for (seen, buffer) in self.format_seen.iter_mut().zip(&rec.format) {
+            *seen |= buffer.is_present();
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: Tally) {
+        self.records += other.records;
+        self.rows += other.rows;
+        self.without_alt += other.without_alt;
+        self.skipped += other.skipped;
+        if other.contig_rows.len() > self.contig_rows.len() {
+            self.contig_rows.resize(other.contig_rows.len(), 0);
+        }
+        for (count, other) in self.contig_rows.iter_mut().zip(other.contig_rows) {
+            *count += other;
+        }
+        for (seen, other) in self.info_seen.iter_mut().zip(other.info_seen) {
+            *seen |= other;
+        }
+        for (seen, other) in self.format_seen.iter_mut().zip(other.format_seen) {
+            *seen |= other;
+        }
+    }
+
+    pub(crate) fn report(
+        self,
+        plan: &HeaderPlan,
+        header: &HeaderView,
+        elapsed: Duration,
+    ) -> ConversionReport {
+        let unseen = |tags: &[TagPlan], seen: &[bool]| {
+            tags.iter()
+                .zip(seen)
+                .filter(|(_, &seen)| !seen)
+                .map(|(tag, _)| tag.name.clone())
+                .collect()
+        };
+        ConversionReport {
+            records_read: self.records,
+            rows_written: self.rows,
+            sites_without_alt: self.without_alt,
+            records_skipped: self.skipped,
+            rows_per_contig: self
+                .contig_rows
+                .iter()
+                .enumerate()
+                .filter(|(_, &rows)| rows > 0)
+                .map(|(rid, &rows)| {
+                    let name = header.rid2name(rid as u32).unwrap_or_default();
+                    (String::from_utf8_lossy(name).into_owned(), rows)
+                })
+                .collect(),
+            unseen_info_tags: unseen(&plan.info, &self.info_seen),
+            unseen_format_tags: unseen(&plan.format, &self.format_seen),
+            elapsed,
+            provenance: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::convert::{ ConversionOptions, ErrorPolicy, Input };
+    use crate::testing;
+
+    #[test]
+    fn counts_what_was_read_written_and_left_out() {
+        let text = testing::vcf(&[
+            "1\t100\t.\tA\tC,G\t.\t.\tDP=5\tGT:DP\t0/1:3\t1/2:4",
+            // Unreadable, having one sample of two
+            "1\t200\t.\tA\tC\t.\t.\tDP=5\tGT:DP\t0/1:3",
+            "1\t300\t.\tT\t.\t.\t.\tDP=2\tGT\t0/0\t0/0",
+            "1\t400\t.\tG\tA\t.\t.\t.\tGT\t0/1\t./.",
+            "2\t50\t.\tC\tT\t.\t.\tDP=1\tGT\t1/1\t0/1",
+        ])
+        .replacen("#CHROM", "##contig=<ID=2,length=1000>\n#CHROM", 1);
+        let file = testing::vcf_file(&text);
+        let options = ConversionOptions::new().on_error(ErrorPolicy::Skip);
+        let (tsv, report) = testing::run(Input::path(file.path()), options).unwrap();
+        assert_eq!(report.records_read, 4);
+        assert_eq!(report.rows_written, 4);
+        assert_eq!(tsv.lines().count() as u64, 2 + report.rows_written);
+        assert_eq!(report.sites_without_alt, 1);
+        assert_eq!(report.records_skipped, 1);
+        assert_eq!(report.rows_per_contig, [("1".to_owned(), 3), ("2".to_owned(), 1)]);
+        assert_eq!(report.unseen_info_tags, ["AF", "RC", "DB"]);
+        assert_eq!(report.unseen_format_tags, ["AD", "PL"]);
+    }
+}
@@ -0,0 +1,311 @@
+use rust_htslib::bcf::header::TagType;
+
+use crate::convert::{ ConversionOptions, OutputFormat };
+use crate::json::Json;
+use crate::plan::{ Column, ColumnKind, ColumnSource, Derived, HeaderDefinitions, HeaderPlan };
+use crate::plan::{ TagPlan, FIXED_COLUMNS };
+use crate::provenance::Provenance;
+use crate::writer::Layout;
+
+/// Descriptions of the site-level columns, in `FIXED_COLUMNS` order.
+const FIXED_DESCRIPTIONS: &[&str] = &[
+    "Chromosome",
+    "1-based position",
+    "Reference allele",
+    "The alternate allele of the row",
+    "Phred-scaled quality score",
+    "Filters the site failed, `;`-separated, or PASS",
+];
+
+/// Everything known about one output column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnSchema {
+    /// The name in the last header line of the output, or the key path of a JSON Lines value.
+    pub name: String,
+    pub column: Column,
+    /// `fixed`, `info`, `format`, or `derived` for columns computed from other tags.
+    pub origin: &'static str,
+    /// The INFO or FORMAT tag the values come from.
+    pub tag: Option<String>,
+    /// `Type` of the source tag in the header.
+    pub vcf_type: Option<String>,
+    /// `Number` of the source tag in the header.
+    pub vcf_number: Option<String>,
+    pub description: String,
+    /// The pandas dtype to load the column as, nullable where values can be missing, and an
+    /// Arrow-backed list for the arrays of JSON Lines.
+    pub pandas_dtype: &'static str,
+    pub arrow_type: &'static str,
+}
+
+/// Whether `columns` describes the output of `format`: a single table of the planned columns.
+pub fn describes(format: OutputFormat, genotype_table: bool) -> bool {
+    let table = matches!(
+        format,
+        OutputFormat::Tsv
+            | OutputFormat::JsonLines
+            | OutputFormat::PostgresText
+            | OutputFormat::PostgresBinary
+    );
+    table && !genotype_table
+}
+
+/// The data dictionary of a conversion: its columns described from the header definitions, and
+/// named as `options.format` names them.
+pub fn columns(
+    plan: &HeaderPlan,
+    definitions: &HeaderDefinitions,
+    options: &ConversionOptions,
+) -> Vec<ColumnSchema> {
+    let sourced = plan.sourced_columns();
+    let columns: Vec<Column> = sourced.iter().map(|(column, _)| column.clone()).collect();
+    let names = match options.format {
+        OutputFormat::Tsv => options.layout.header_rows(&columns).pop().unwrap_or_default(),
+        OutputFormat::JsonLines => columns.iter().map(json_key).collect(),
+        // The columns of a COPY are those of the flat layout
+        _ => Layout::Flat.header_rows(&columns).pop().unwrap_or_default(),
+    };
+    sourced
+        .into_iter()
+        .zip(names)
+        .map(|((column, source), name)| {
+            match source {
+                ColumnSource::Fixed => fixed_column(name, column),
+                ColumnSource::Tag(tag) => {
+                    // JSON Lines keeps every value of such tags, in an array; FORMAT strings are
+                    // left whole
+                    let list = options.format == OutputFormat::JsonLines
+                        && tag.is_multi_valued()
+                        && (column.kind == ColumnKind::Info || tag.tag_type != TagType::String);
+                    tag_column(name, column, definitions, tag, list)
+                }
+                ColumnSource::Derived(derived) => derived_column(name, column, derived),
+            }
+        })
+        .collect()
+}
+
+/// The path to the value of `column` within a JSON Lines object, as `pandas.json_normalize`
+/// names it.
+fn json_key(column: &Column) -> String {
+    match &column.kind {
+        ColumnKind::Fixed => column.name.clone(),
+        ColumnKind::Info => format!("info.{}", column.name),
+        ColumnKind::Format { sample } => format!("samples.{}.{}", sample, column.name),
+    }
+}
+
+fn fixed_column(name: String, column: Column) -> ColumnSchema {
+    let description = FIXED_COLUMNS
+        .iter()
+        .position(|fixed| *fixed == column.name)
+        .and_then(|i| FIXED_DESCRIPTIONS.get(i))
+        .copied()
+        .unwrap_or_default();
+    let (pandas_dtype, arrow_type) = match column.name.as_str() {
+        "POS" => ("Int64", "int64"),
+        "QUAL" => ("float32", "float"),
+        _ => ("string", "string"),
+    };
+    ColumnSchema {
+        name,
+        column,
+        origin: "fixed",
+        tag: None,
+        vcf_type: None,
+        vcf_number: None,
+        description: description.to_owned(),
+        pandas_dtype,
+        arrow_type,
+    }
+}
+
+fn tag_column(
+    name: String,
+    column: Column,
+    definitions: &HeaderDefinitions,
+    tag: &TagPlan,
+    list: bool,
+) -> ColumnSchema {
+    let (origin, definitions) = match column.kind {
+        ColumnKind::Info => ("info", &definitions.info),
+        _ => ("format", &definitions.format),
+    };
+    let definition = definitions.iter().find(|definition| definition.id == tag.name);
+    let (pandas_dtype, arrow_type) = dtypes(column.value_type, list);
+    ColumnSchema {
+        name,
+        column,
+        origin,
+        tag: Some(tag.name.clone()),
+        vcf_type: definition.and_then(|d| d.tag_type.clone()),
+        vcf_number: definition.and_then(|d| d.number.clone()),
+        description: definition.map_or_else(String::new, |d| d.description.clone()),
+        pandas_dtype,
+        arrow_type,
+    }
+}
+
+fn derived_column(name: String, column: Column, derived: Derived) -> ColumnSchema {
+    let (pandas_dtype, arrow_type) = dtypes(column.value_type, false);
+    ColumnSchema {
+        name,
+        column,
+        origin: "derived",
+        tag: None,
+        vcf_type: None,
+        vcf_number: None,
+        description: derived.description().to_owned(),
+        pandas_dtype,
+        arrow_type,
+    }
+}
+
+/// The pandas and Arrow types of tag values, nullable where values can be missing, or of lists
+/// of them.
+fn dtypes(value_type: TagType, list: bool) -> (&'static str, &'static str) {
+    match (value_type, list) {
+        (TagType::Flag, _) => ("boolean", "bool"),
+        (TagType::Integer, false) => ("Int32", "int32"),
+        (TagType::Float, false) => ("float32", "float"),
+        (TagType::String, false) => ("string", "string"),
+        (TagType::Integer, true) => ("list<item: int32>[pyarrow]", "list<item: int32>"),
+        (TagType::Float, true) => ("list<item: float>[pyarrow]", "list<item: float>"),
+        (TagType::String, true) => ("list<item: string>[pyarrow]", "list<item: string>"),
+    }
+}
+
+/// The data dictionary as the JSON document written next to the output.
+pub(crate) fn to_json(
+    columns: &[ColumnSchema],
+    options: &ConversionOptions,
+    provenance: Option<&Provenance>,
+) -> String {
+    let columns = columns.iter().map(|schema| {
+        let sample = match &schema.column.kind {
+            ColumnKind::Format { sample } => Some(sample.as_str()),
+            _ => None,
+        };
+        Json::object([
+            ("name", schema.name.as_str().into()),
+            ("origin", schema.origin.into()),
+            ("tag", schema.tag.as_deref().into()),
+            ("sample", sample.into()),
+            ("vcf_type", schema.vcf_type.clone().into()),
+            ("vcf_number", schema.vcf_number.clone().into()),
+            ("description", schema.description.as_str().into()),
+            ("pandas_dtype", schema.pandas_dtype.into()),
+            ("arrow_type", schema.arrow_type.into()),
+        ])
+    });
+    // Only text tables have delimiters and missing values of their own, and only TSV a header
+    let (delimiter, missing) = match options.format {
+        OutputFormat::Tsv => (Some("\t"), Some("")),
+        OutputFormat::PostgresText => (Some("\t"), Some("\\N")),
+        _ => (None, None),
+    };
+    let header_lines = match (options.format, options.layout) {
+        (OutputFormat::Tsv, Layout::Grouped) => 2,
+        (OutputFormat::Tsv, Layout::Flat) => 1,
+        _ => 0,
+    };
+    let comment = options.format == OutputFormat::Tsv && (options.metadata || options.provenance);
+    Json::object([
+        ("format", options.format.to_string().into()),
+        ("delimiter", delimiter.into()),
+        ("comment", comment.then_some("#").into()),
+        ("header_lines", Json::Int(header_lines)),
+        ("missing", missing.into()),
+        ("columns", Json::Array(columns.collect())),
+        ("provenance", provenance.map_or(Json::Null, provenance_json)),
+    ])
+    .pretty()
+}
+
+fn provenance_json(provenance: &Provenance) -> Json {
+    let options = provenance.options.iter().map(|(name, value)| (name.as_str(), value.into()));
+    Json::object([
+        ("version", provenance.version.into()),
+        ("options", Json::object(options)),
+        ("input", provenance.input.as_str().into()),
+        ("input_size", provenance.input_size.map(|size| Json::Int(size as i64)).into()),
+        ("input_sha256", provenance.input_sha256.clone().into()),
+        ("started", provenance.started.as_str().into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::TagSelection;
+    use crate::testing;
+
+    /// The name and pandas and Arrow types of the INFO and FORMAT columns of `format`.
+    fn dtypes(format: OutputFormat) -> Vec<(String, &'static str, &'static str)> {
+        let text = testing::multi_valued_vcf(&[]);
+        let (header, _) = testing::read(&text);
+        let info = TagSelection::only(["DP", "MQ", "XS"]);
+        let format_tags = TagSelection::only(["GT", "HQ", "FT"]);
+        let plan = HeaderPlan::new(&header, &info, &format_tags).unwrap();
+        let options = ConversionOptions::new().format(format);
+        columns(&plan, &HeaderDefinitions::new(&header), &options)
+            .into_iter()
+            .filter(|schema| schema.origin != "fixed")
+            .take(6)
+            .map(|schema| (schema.name, schema.pandas_dtype, schema.arrow_type))
+            .collect()
+    }
+
+    #[test]
+    fn types_json_lines_arrays_as_lists() {
+        let column = |name: &str, pandas, arrow| (name.to_owned(), pandas, arrow);
+        assert_eq!(
+            dtypes(OutputFormat::JsonLines),
+            [
+                column("info.DP", "Int32", "int32"),
+                column("info.MQ", "list<item: int32>[pyarrow]", "list<item: int32>"),
+                column("info.XS", "list<item: string>[pyarrow]", "list<item: string>"),
+                column("samples.s1.GT", "string", "string"),
+                column("samples.s1.HQ", "list<item: float>[pyarrow]", "list<item: float>"),
+                // Written whole, as one string
+                column("samples.s1.FT", "string", "string"),
+            ]
+        );
+        // Tables keep the first value only, and name FORMAT columns under their sample
+        assert_eq!(
+            dtypes(OutputFormat::Tsv),
+            [
+                column("DP", "Int32", "int32"),
+                column("MQ", "Int32", "int32"),
+                column("XS", "string", "string"),
+                column("GT", "string", "string"),
+                column("HQ", "float32", "float"),
+                column("FT", "string", "string"),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_the_dtypes_of_each_column() {
+        let text = testing::multi_valued_vcf(&[]);
+        let (header, _) = testing::read(&text);
+        let plan = HeaderPlan::new(
+            &header,
+            &TagSelection::only(["MQ"]),
+            &TagSelection::Only(Vec::new()),
+        )
+        .unwrap();
+        let options = ConversionOptions::new().format(OutputFormat::JsonLines);
+        let columns = columns(&plan, &HeaderDefinitions::new(&header), &options);
+        let json = to_json(&columns, &options, None);
+        // JSON Lines output has neither delimiters nor header lines
+        assert!(json.contains("\"delimiter\": null,\n  \"comment\": null,\n  \"header_lines\": 0,"));
+        assert!(json.contains(
+            "\"name\": \"info.MQ\",\n      \"origin\": \"info\",\n      \"tag\": \"MQ\","
+        ));
+        assert!(json.contains(
+            "\"pandas_dtype\": \"list<item: int32>[pyarrow]\",\n      \
+             \"arrow_type\": \"list<item: int32>\"\n    }\n  ],"
+        ));
+    }
+}
@@ -0,0 +1,80 @@
+use rust_htslib::bcf::record::Numeric;
+
+use crate::format::split_genotype;
+use crate::plan::Column;
+
+/// Receives the converted table one typed value at a time.
+///
+/// `metadata` may be called first, then `begin` once with the output columns, then every row is
+/// framed by `begin_row` and `end_row` and holds exactly one value per column, in column order.
+/// Missing values are reported through `write_null` rather than as htslib sentinels.
+pub trait RowSink {
+    /// Key/value pairs describing where the table comes from, e.g. the meta-information lines of
+    /// the VCF header. Backends without a place for them ignore them.
+    fn metadata(&mut self, _entries: &[(String, String)]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()>;
+
+    /// Called before the rows of each VCF record, one per ALT allele, for backends that store
+    /// the fields of a site once.
+    fn begin_record(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()>;
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()>;
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()>;
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()>;
+
+    fn write_null(&mut self) -> anyhow::Result<()>;
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()>;
+
+    /// Every value of a tag with several per row, e.g. `Number=2` or `Number=.`. Backends
+    /// without lists keep the first value, like the text table.
+    fn write_ints(&mut self, values: &[i32]) -> anyhow::Result<()> {
+        match values.first() {
+            Some(v) if !v.is_missing() => self.write_int(*v),
+            _ => self.write_null(),
+        }
+    }
+
+    /// As `write_ints`.
+    fn write_floats(&mut self, values: &[f32]) -> anyhow::Result<()> {
+        match values.first() {
+            Some(v) if !v.is_missing() => self.write_float(*v),
+            _ => self.write_null(),
+        }
+    }
+
+    /// As `write_ints`, for the comma-separated values of an INFO string.
+    fn write_strs(&mut self, values: &mut dyn Iterator<Item = &[u8]>) -> anyhow::Result<()> {
+        match values.next() {
+            Some(v) => self.write_str(v),
+            None => self.write_null(),
+        }
+    }
+
+    /// A genotype as htslib encodes it, in the row of ALT allele `allele` (1-based), padded
+    /// with the vector end marker for samples of a lower ploidy. Backends without a use for the
+    /// alleles write the text of the table: `0` for REF, `1` for `allele` and `.` otherwise.
+    fn write_genotype(&mut self, encoded: &[i32], allele: usize) -> anyhow::Result<()> {
+        self.write_str(&split_genotype(encoded, allele))
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()>;
+
+    /// The rows left out of the output so far, e.g. the second record of a breakend pair in
+    /// BEDPE; every other row counts as written.
+    fn rows_dropped(&self) -> u64 {
+        0
+    }
+
+    /// Called once after the last row; flush and close the underlying output here.
+    fn finish(&mut self) -> anyhow::Result<()>;
+}
@@ -0,0 +1,281 @@
+use rust_htslib::bcf::header::HeaderView;
+use std::io::Write;
+
+use crate::format::split_genotype;
+use crate::plan::{ Column, ColumnKind, Derived, HeaderDefinitions, HeaderPlan, TagSelection };
+use crate::sink::RowSink;
+use crate::writer::{ Layout, Writer };
+
+/// The columns written for each of the tumor and normal samples, after their prefix.
+const CALL_COLUMNS: &[&str] = &["GT", "depth", "alt_count", "VAF"];
+
+/// The plan of a tumor/normal view: the site fields, the selected INFO tags, and the GT, the
+/// REF and ALT AD, the depth and the VAF of every sample.
+pub(crate) fn plan(header: &HeaderView, info: &TagSelection) -> anyhow::Result<HeaderPlan> {
+    let definitions = HeaderDefinitions::new(header);
+    let format = ["GT", "AD"]
+        .into_iter()
+        .filter(|name| definitions.format.iter().any(|tag| tag.id == *name));
+    let mut plan = HeaderPlan::new(header, info, &TagSelection::only(format))?;
+    plan.split_allele_depths();
+    plan.derived = vec![Derived::Depth, Derived::Vaf];
+    Ok(plan)
+}
+
+/// What a value of the planned row is used for; calls are indexed tumor first, then normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Site,
+    Genotype(usize),
+    AltDepth(usize),
+    Depth(usize),
+    Vaf(usize),
+    Unused,
+}
+
+/// What the row of an ALT allele says about the tumor or normal sample.
+#[derive(Debug, Default)]
+struct Call {
+    genotype: Option<[u8; 3]>,
+    alt_depth: Option<i32>,
+    depth: Option<i32>,
+    vaf: Option<f32>,
+}
+
+/// Writes rows as a somatic view of a tumor sample and its matched normal, one line per ALT
+/// allele.
+///
+/// The site fields and INFO tags are followed by the GT, depth, ALT count and variant allele
+/// frequency (VAF) of the tumor, the same for the normal, and the tumor VAF minus the normal
+/// one. The ALT count comes from AD, and the depth and VAF are the derived columns of the same
+/// names.
+pub struct SomaticWriter<W: Write> {
+    writer: Writer<W>,
+    tumor: String,
+    normal: String,
+    fields: Vec<Field>,
+    /// Index of the next value in the current row.
+    column: usize,
+    calls: [Call; 2],
+}
+
+impl<W: Write> SomaticWriter<W> {
+    pub fn new(inner: W, tumor: String, normal: String) -> Self {
+        SomaticWriter {
+            writer: Writer::new(inner, Layout::Flat),
+            tumor,
+            normal,
+            fields: Vec::new(),
+            column: 0,
+            calls: Default::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+
+    fn next_field(&mut self) -> Field {
+        let field = self.fields.get(self.column).copied().unwrap_or(Field::Unused);
+        self.column += 1;
+        field
+    }
+
+    fn write_count(&mut self, value: Option<i32>) -> anyhow::Result<()> {
+        match value {
+            Some(value) => self.writer.write_int(value),
+            None => self.writer.write_null(),
+        }
+    }
+
+    fn write_fraction(&mut self, value: Option<f32>) -> anyhow::Result<()> {
+        match value {
+            Some(value) => self.writer.write_float(value),
+            None => self.writer.write_null(),
+        }
+    }
+}
+
+impl<W: Write> RowSink for SomaticWriter<W> {
+    fn metadata(&mut self, entries: &[(String, String)]) -> anyhow::Result<()> {
+        self.writer.metadata(entries)
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        self.fields = columns
+            .iter()
+            .map(|column| {
+                let call = match &column.kind {
+                    ColumnKind::Fixed | ColumnKind::Info => return Field::Site,
+                    ColumnKind::Format { sample } if *sample == self.tumor => 0,
+                    ColumnKind::Format { sample } if *sample == self.normal => 1,
+                    ColumnKind::Format { .. } => return Field::Unused,
+                };
+                match column.name.as_str() {
+                    "GT" => Field::Genotype(call),
+                    "AD_ALT" => Field::AltDepth(call),
+                    "depth" => Field::Depth(call),
+                    "VAF" => Field::Vaf(call),
+                    _ => Field::Unused,
+                }
+            })
+            .collect();
+
+        let site: Vec<Column> = columns
+            .iter()
+            .filter(|column| !matches!(column.kind, ColumnKind::Format { .. }))
+            .cloned()
+            .collect();
+        for name in Layout::Flat.header_rows(&site).pop().unwrap_or_default() {
+            self.writer.write_field(name.as_bytes())?;
+        }
+        for prefix in ["tumor", "normal"] {
+            for name in CALL_COLUMNS {
+                self.writer.write_field(format!("{}_{}", prefix, name).as_bytes())?;
+            }
+        }
+        self.writer.write_field(b"VAF_difference")?;
+        self.writer.newline()
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.column = 0;
+        self.calls = Default::default();
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        match self.next_field() {
+            Field::Site => self.writer.write_int(value)?,
+            Field::AltDepth(call) => self.calls[call].alt_depth = Some(value),
+            Field::Depth(call) => self.calls[call].depth = Some(value),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        match self.next_field() {
+            Field::Site => self.writer.write_float(value)?,
+            Field::Vaf(call) => self.calls[call].vaf = Some(value),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        if self.next_field() == Field::Site {
+            self.writer.write_str(value)?;
+        }
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        if self.next_field() == Field::Site {
+            self.writer.write_null()?;
+        }
+        Ok(())
+    }
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()> {
+        if self.next_field() == Field::Site {
+            self.writer.write_flag(value)?;
+        }
+        Ok(())
+    }
+
+    fn write_ints(&mut self, values: &[i32]) -> anyhow::Result<()> {
+        if self.next_field() == Field::Site {
+            self.writer.write_ints(values)?;
+        }
+        Ok(())
+    }
+
+    fn write_floats(&mut self, values: &[f32]) -> anyhow::Result<()> {
+        if self.next_field() == Field::Site {
+            self.writer.write_floats(values)?;
+        }
+        Ok(())
+    }
+
+    fn write_strs(&mut self, values: &mut dyn Iterator<Item = &[u8]>) -> anyhow::Result<()> {
+        if self.next_field() == Field::Site {
+            self.writer.write_strs(values)?;
+        }
+        Ok(())
+    }
+
+    fn write_genotype(&mut self, encoded: &[i32], allele: usize) -> anyhow::Result<()> {
+        if let Field::Genotype(call) = self.next_field() {
+            self.calls[call].genotype = Some(split_genotype(encoded, allele));
+        }
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        for call in 0..2 {
+            let call = &self.calls[call];
+            let genotype = call.genotype;
+            let (depth, alt_depth, vaf) = (call.depth, call.alt_depth, call.vaf);
+            match genotype {
+                Some(genotype) => self.writer.write_field(&genotype)?,
+                None => self.writer.write_null()?,
+            }
+            self.write_count(depth)?;
+            self.write_count(alt_depth)?;
+            self.write_fraction(vaf)?;
+        }
+        let difference = self.calls[0].vaf.zip(self.calls[1].vaf);
+        self.write_fraction(difference.map(|(tumor, normal)| tumor - normal))?;
+        self.writer.newline()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// The somatic view of the VCF `records`, s1 being the tumor and s2 the normal, with INFO DP.
+    fn lines(records: &[&str]) -> Vec<String> {
+        let writer = testing::written(
+            &testing::vcf(records),
+            |header| plan(header, &TagSelection::only(["DP"])).unwrap(),
+            |_| SomaticWriter::new(Vec::new(), "s1".into(), "s2".into()),
+        );
+        String::from_utf8(writer.into_inner()).unwrap().lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn writes_both_calls_and_their_vaf_difference() {
+        let lines = lines(&[
+            "1\t100\t.\tA\tG\t.\tPASS\tDP=20\tGT:AD:DP\t0/1:6,4:12\t0/0:9,1:10",
+            "1\t200\t.\tC\tT,G\t.\t.\t.\tGT:AD\t1/2:2,5,3\t0/0:8,0,0",
+        ]);
+        assert_eq!(
+            lines[0],
+            "CHROM\tPOS\tREF\tALT\tQUAL\tFILTER\tDP\t\
+             tumor_GT\ttumor_depth\ttumor_alt_count\ttumor_VAF\t\
+             normal_GT\tnormal_depth\tnormal_alt_count\tnormal_VAF\tVAF_difference"
+        );
+        // The depth is DP, else the sum of AD, and the VAF is over the sum of AD either way
+        assert_eq!(
+            lines[1..],
+            [
+                "1\t100\tA\tG\t\tPASS\t20\t0/1\t12\t4\t0.4\t0/0\t10\t1\t0.1\t0.3",
+                "1\t200\tC\tT\t\t\t\t1/.\t10\t5\t0.5\t0/0\t8\t0\t0\t0.5",
+                "1\t200\tC\tG\t\t\t\t./1\t10\t3\t0.3\t0/0\t8\t0\t0\t0.3",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_a_missing_normal_call_empty() {
+        let lines = lines(&["1\t300\t.\tG\tA\t.\t.\t.\tGT:AD\t0/1:3,3\t./.:."]);
+        assert_eq!(lines[1], "1\t300\tG\tA\t\t\t\t0/1\t6\t3\t0.5\t./.\t\t\t\t");
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{ bail, Context };
+use rust_htslib::bcf;
+use std::fs::File;
+use std::io::{ self, Read, Write };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
+
+use crate::convert::Input;
+
+/// Bytes copied from an input into the pipe of its reader at a time.
+const FEED_BUFFER: usize = 64 * 1024;
+
+/// An open input, with the thread feeding it to htslib when htslib cannot read it on its own.
+pub(crate) struct Source {
+    pub reader: bcf::Reader,
+    /// How much of the input file `reader` was handed, when it reads one through a pipe.
+    pub offset: Option<FileOffset>,
+    /// The thread feeding `reader` from the input.
+    feed: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Source {
+    /// Open `input`, reading a file through a pipe when `offset` asks how much of it is read.
+    /// Streams are always read through a pipe, as htslib only reads from paths.
+    pub fn open(input: Input, offset: bool) -> anyhow::Result<Self> {
+        match input {
+            Input::Path(path) if offset && cfg!(unix) => {
+                let file =
+                    File::open(&path).with_context(|| format!("cannot open {}", path.display()))?;
+                let offset = FileOffset { read: Arc::default(), size: file.metadata()?.len() };
+                Source::piped(file, Some(offset))
+                    .with_context(|| format!("cannot read {}", path.display()))
+            }
+            Input::Path(path) => {
+                let reader = bcf::Reader::from_path(&path)
+                    .with_context(|| format!("cannot read {}", path.display()))?;
+                Ok(Source::direct(reader))
+            }
+            Input::Stdin => Ok(Source::direct(bcf::Reader::from_stdin()?)),
+            Input::Reader(bytes) => Source::piped(bytes, None).context("cannot read the input"),
+        }
+    }
+
+    /// A reader htslib reads its input with on its own.
+    fn direct(reader: bcf::Reader) -> Self {
+        Source { reader, offset: None, feed: None }
+    }
+
+    /// Feed `input` to htslib through a pipe, counting the bytes handed over in `offset`.
+    #[cfg(unix)]
+    fn piped<R: Read + Send + 'static>(
+        input: R,
+        offset: Option<FileOffset>,
+    ) -> anyhow::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let (output, pipe) = rustix::pipe::pipe()?;
+        let read = offset.as_ref().map_or_else(Arc::default, |offset| Arc::clone(&offset.read));
+        let feed = thread::spawn(move || pump(input, File::from(pipe), &read));
+        // Opened by htslib in its own right, after which this end is no longer needed
+        let reader = bcf::Reader::from_path(format!("/dev/fd/{}", output.as_raw_fd()));
+        drop(output);
+        Ok(Source { reader: reader?, offset, feed: Some(feed) })
+    }
+
+    #[cfg(not(unix))]
+    fn piped<R: Read + Send + 'static>(
+        _input: R,
+        _offset: Option<FileOffset>,
+    ) -> anyhow::Result<Self> {
+        bail!("reading a stream needs a pipe, which is only supported on Unix")
+    }
+
+    /// Close the input, failing if it could not be read through to its end.
+    pub fn close(self) -> anyhow::Result<()> {
+        let Source { reader, feed, .. } = self;
+        // Closing the pipe stops a feed that is still ahead of the reader
+        drop(reader);
+        match feed.map(JoinHandle::join) {
+            Some(Ok(result)) => Ok(result.context("cannot read the input")?),
+            Some(Err(_)) => bail!("the thread reading the input panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How much of an input file has been handed to its reader.
+#[derive(Clone)]
+pub(crate) struct FileOffset {
+    read: Arc<AtomicU64>,
+    size: u64,
+}
+
+impl FileOffset {
+    /// The fraction of the file handed over, which is at most a pipe buffer ahead of the
+    /// reader. For a bgzipped file, this is its compressed offset over its size.
+    pub fn fraction(&self) -> Option<f64> {
+        let read = self.read.load(Ordering::Relaxed);
+        (self.size > 0).then(|| (read as f64 / self.size as f64).min(1.0))
+    }
+}
+
+/// Copy `input` into `pipe`, adding the bytes copied to `read`. A reader closing its end before
+/// the end of the input is no error.
+fn pump(mut input: impl Read, mut pipe: File, read: &AtomicU64) -> io::Result<()> {
+    let mut buffer = vec![0; FEED_BUFFER];
+    loop {
+        let len = match input.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        match pipe.write_all(&buffer[..len]) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            result => result?,
+        }
+        read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_htslib::bcf::Read;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn reads_a_file_through_a_pipe_only_to_track_its_offset() {
+        let text = testing::vcf(&["1\t100\t.\tA\tC\t.\t.\t.\tGT\t0/1\t0/0"]);
+        let file = testing::vcf_file(&text);
+        let source = Source::open(Input::path(file.path()), false).unwrap();
+        assert!(source.offset.is_none() && source.feed.is_none());
+
+        let mut source = Source::open(Input::path(file.path()), true).unwrap();
+        assert_eq!(source.reader.records().count(), 1);
+        assert_eq!(source.offset.as_ref().and_then(FileOffset::fraction), Some(1.0));
+        source.close().unwrap();
+    }
+}
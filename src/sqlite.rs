@@ -0,0 +1,325 @@
+use itertools::Itertools;
+use rusqlite::types::Value;
+use rusqlite::{ params_from_iter, Connection };
+use rust_htslib::bcf::header::TagType;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::plan::{ Column, ColumnKind };
+use crate::sink::RowSink;
+
+/// Rows inserted per transaction.
+const BATCH_ROWS: u64 = 10_000;
+/// The most columns a SQLite table can have, `SQLITE_MAX_COLUMN` as SQLite is built by default.
+const MAX_COLUMNS: usize = 2000;
+
+/// Writes rows into a new SQLite database.
+///
+/// Site fields and INFO tags go to a `variants` table, with a column per field typed from the
+/// header. FORMAT values are either more `variants` columns, named `<sample>_<TAG>`, or rows
+/// of a long `genotypes` table keyed by `variant_id` and `sample`. Header metadata, if any,
+/// goes to a `metadata` table.
+pub struct SqliteWriter {
+    connection: Connection,
+    genotype_table: bool,
+    variants_insert: String,
+    genotypes_insert: String,
+    /// Columns of the `variants` table; any after them are FORMAT values per sample.
+    site_columns: usize,
+    samples: Vec<String>,
+    /// Values of the current row, in column order.
+    row: Vec<Value>,
+    rows: u64,
+}
+
+impl SqliteWriter {
+    /// Create the database at `path`, replacing any file there.
+    pub fn create<P: AsRef<Path>>(path: P, genotype_table: bool) -> anyhow::Result<Self> {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        Ok(SqliteWriter {
+            connection: Connection::open(path)?,
+            genotype_table,
+            variants_insert: String::new(),
+            genotypes_insert: String::new(),
+            site_columns: 0,
+            samples: Vec::new(),
+            row: Vec::new(),
+            rows: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> Connection {
+        self.connection
+    }
+}
+
+impl RowSink for SqliteWriter {
+    fn metadata(&mut self, entries: &[(String, String)]) -> anyhow::Result<()> {
+        self.connection.execute_batch("CREATE TABLE metadata (key TEXT NOT NULL, value TEXT)")?;
+        let mut insert = self.connection.prepare("INSERT INTO metadata VALUES (?1, ?2)")?;
+        for (key, value) in entries {
+            insert.execute([key, value])?;
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        let split = columns
+            .iter()
+            .position(|column| matches!(column.kind, ColumnKind::Format { .. }))
+            .filter(|_| self.genotype_table);
+        let (site, format) = columns.split_at(split.unwrap_or(columns.len()));
+        // The variants table also has variant_id
+        if site.len() + 1 > MAX_COLUMNS {
+            anyhow::bail!(
+                "{} columns are too many for a SQLite table, which holds at most {}; select \
+                 fewer tags, or write the FORMAT tags to a long genotypes table with \
+                 genotype_table",
+                site.len() + 1,
+                MAX_COLUMNS
+            );
+        }
+        self.site_columns = site.len();
+
+        let mut variants = vec!["variant_id INTEGER PRIMARY KEY".to_owned()];
+        variants.extend(site.iter().map(|column| {
+            let name = match &column.kind {
+                ColumnKind::Format { sample } => format!("{}_{}", sample, column.name),
+                _ => column.name.clone(),
+            };
+            definition(&name, column.value_type)
+        }));
+        let mut create = format!("CREATE TABLE variants ({});\n", variants.join(", "));
+        // NULL has SQLite assign the next variant_id
+        self.variants_insert =
+            format!("INSERT INTO variants VALUES (NULL, {})", parameters(site.len()));
+
+        // FORMAT columns come grouped by sample, with the same tags in the same order for each
+        self.samples = format
+            .iter()
+            .filter_map(|column| {
+                match &column.kind {
+                    ColumnKind::Format { sample } => Some(sample.clone()),
+                    _ => None,
+                }
+            })
+            .dedup()
+            .collect();
+        if self.genotype_table {
+            let tags = &format[..format.len() / self.samples.len().max(1)];
+            let mut genotypes = vec![
+                "variant_id INTEGER NOT NULL REFERENCES variants (variant_id)".to_owned(),
+                "sample TEXT NOT NULL".to_owned(),
+            ];
+            genotypes.extend(tags.iter().map(|tag| definition(&tag.name, tag.value_type)));
+            create.push_str(&format!("CREATE TABLE genotypes ({});\n", genotypes.join(", ")));
+            self.genotypes_insert =
+                format!("INSERT INTO genotypes VALUES ({})", parameters(tags.len() + 2));
+        }
+        self.connection.execute_batch(&create)?;
+        self.connection.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.row.clear();
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        self.row.push(Value::Integer(value.into()));
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        // Through the shortest decimal form, so that 0.1 is stored as 0.1 rather than as the
+        // double nearest to the float nearest to 0.1
+        self.row.push(Value::Real(value.to_string().parse()?));
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        self.row.push(Value::Text(String::from_utf8_lossy(value).into_owned()));
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        self.row.push(Value::Null);
+        Ok(())
+    }
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()> {
+        self.row.push(Value::Integer(value.into()));
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        let (site, format) = self.row.split_at(self.site_columns);
+        self.connection.prepare_cached(&self.variants_insert)?.execute(params_from_iter(site))?;
+        if self.genotype_table && !self.samples.is_empty() {
+            let id = Value::Integer(self.connection.last_insert_rowid());
+            let mut insert = self.connection.prepare_cached(&self.genotypes_insert)?;
+            let tags = format.len() / self.samples.len();
+            for (sample, values) in self.samples.iter().zip(format.chunks(tags.max(1))) {
+                let sample = Value::Text(sample.clone());
+                insert.execute(params_from_iter([&id, &sample].into_iter().chain(values)))?;
+            }
+        }
+        self.rows += 1;
+        if self.rows.is_multiple_of(BATCH_ROWS) {
+            self.connection.execute_batch("COMMIT; BEGIN")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.connection.execute_batch("COMMIT")?;
+        // Indexing once loaded is faster than keeping the indexes up to date while inserting
+        let mut indexes = String::from("CREATE INDEX variants_locus ON variants (CHROM, POS);\n");
+        if self.genotype_table {
+            indexes.push_str(
+                "CREATE INDEX genotypes_variant ON genotypes (variant_id);\n\
+                 CREATE INDEX genotypes_sample ON genotypes (sample);\n",
+            );
+        }
+        self.connection.execute_batch(&indexes)?;
+        Ok(())
+    }
+}
+
+/// A column definition with the SQLite type of `value_type`; flags are stored as 0 or 1.
+fn definition(name: &str, value_type: TagType) -> String {
+    let sql_type = match value_type {
+        TagType::Flag | TagType::Integer => "INTEGER",
+        TagType::Float => "REAL",
+        TagType::String => "TEXT",
+    };
+    format!("{} {}", quote(name), sql_type)
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// `count` numbered statement parameters.
+fn parameters(count: usize) -> String {
+    (1..=count).map(|i| format!("?{}", i)).join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{ HeaderPlan, TagSelection };
+    use crate::testing;
+
+    const RECORDS: &[&str] = &[
+        "1\t100\t.\tA\tC,G\t50\tPASS\tDP=10;AF=0.25,0.5;DB\tGT:DP\t0/1:7\t1|2:.",
+        "1\t200\t.\tT\tG\t.\t.\t.\tGT\t./.\t0/1",
+    ];
+
+    /// The database `RECORDS` are converted to, with the INFO tags DP, AF and DB and the
+    /// FORMAT tags GT and DP.
+    fn convert(genotype_table: bool) -> (tempfile::TempDir, Connection) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("calls.sqlite");
+        let writer = testing::written(
+            &testing::vcf(RECORDS),
+            |header| {
+                let info = TagSelection::only(["DP", "AF", "DB"]);
+                HeaderPlan::new(header, &info, &TagSelection::only(["GT", "DP"])).unwrap()
+            },
+            |_| SqliteWriter::create(&path, genotype_table).unwrap(),
+        );
+        (dir, writer.into_inner())
+    }
+
+    /// The `CREATE` statement of `table`.
+    fn schema(connection: &Connection, table: &str) -> String {
+        let query = "SELECT sql FROM sqlite_master WHERE name = ?1";
+        connection.query_row(query, [table], |row| row.get(0)).unwrap()
+    }
+
+    /// Every row of `query`, each value as text after its SQLite type.
+    fn rows(connection: &Connection, query: &str) -> Vec<String> {
+        let mut statement = connection.prepare(query).unwrap();
+        let columns = statement.column_count();
+        let rows = statement.query_map([], |row| {
+            let values = (0..columns).map(|i| {
+                Ok(match row.get::<_, Value>(i)? {
+                    Value::Null => "NULL".to_owned(),
+                    Value::Integer(value) => format!("{}i", value),
+                    Value::Real(value) => format!("{}r", value),
+                    Value::Text(value) => value,
+                    Value::Blob(_) => "BLOB".to_owned(),
+                })
+            });
+            values.collect::<rusqlite::Result<Vec<_>>>().map(|values| values.join(" "))
+        });
+        rows.unwrap().collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn types_a_column_per_field_and_sample() {
+        let (_dir, connection) = convert(false);
+        assert_eq!(
+            schema(&connection, "variants"),
+            "CREATE TABLE variants (variant_id INTEGER PRIMARY KEY, \"CHROM\" TEXT, \
+             \"POS\" INTEGER, \"REF\" TEXT, \"ALT\" TEXT, \"QUAL\" REAL, \"FILTER\" TEXT, \
+             \"DP\" INTEGER, \"AF\" REAL, \"DB\" INTEGER, \"s1_GT\" TEXT, \"s1_DP\" INTEGER, \
+             \"s2_GT\" TEXT, \"s2_DP\" INTEGER)"
+        );
+        assert_eq!(
+            rows(&connection, "SELECT * FROM variants"),
+            [
+                "1i 1 100i A C 50r PASS 10i 0.25r 1i 0/1 7i 1|. NULL",
+                "2i 1 100i A G 50r PASS 10i 0.5r 1i 0/. 7i .|1 NULL",
+                "3i 1 200i T G NULL NULL NULL NULL 0i ./. NULL 0/1 NULL",
+            ]
+        );
+        assert!(schema(&connection, "variants_locus").contains("(CHROM, POS)"));
+    }
+
+    #[test]
+    fn writes_format_values_to_a_long_genotypes_table() {
+        let (_dir, connection) = convert(true);
+        assert_eq!(
+            schema(&connection, "genotypes"),
+            "CREATE TABLE genotypes (variant_id INTEGER NOT NULL REFERENCES variants \
+             (variant_id), sample TEXT NOT NULL, \"GT\" TEXT, \"DP\" INTEGER)"
+        );
+        assert_eq!(
+            rows(&connection, "SELECT * FROM genotypes"),
+            [
+                "1i s1 0/1 7i",
+                "1i s2 1|. NULL",
+                "2i s1 0/. 7i",
+                "2i s2 .|1 NULL",
+                "3i s1 ./. NULL",
+                "3i s2 0/1 NULL",
+            ]
+        );
+        assert_eq!(rows(&connection, "SELECT count(*) FROM variants"), ["3i"]);
+    }
+
+    #[test]
+    fn rejects_tables_wider_than_sqlite_allows() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SqliteWriter::create(dir.path().join("calls.sqlite"), false).unwrap();
+        let column = |i| Column {
+            kind: ColumnKind::Format { sample: format!("s{}", i) },
+            name: "DP".to_owned(),
+            value_type: TagType::Integer,
+        };
+        let columns: Vec<Column> = (0..MAX_COLUMNS).map(column).collect();
+        let error = writer.begin(&columns).unwrap_err().to_string();
+        assert!(error.starts_with("2001 columns are too many for a SQLite table"));
+        assert!(error.ends_with("genotype_table"));
+        // All in the genotypes table instead
+        let mut writer = SqliteWriter::create(dir.path().join("long.sqlite"), true).unwrap();
+        writer.begin(&columns).unwrap();
+    }
+}
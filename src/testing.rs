@@ -0,0 +1,154 @@
+//! Helpers for the unit tests: small VCFs written inline and converted in memory.
+
+use rust_htslib::bcf::header::HeaderView;
+use rust_htslib::bcf::{ self, Read };
+use rust_htslib::{ bgzf, htslib };
+use std::ffi::CString;
+use std::fs;
+use std::io::{ self, Write };
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+use tempfile::{ NamedTempFile, TempDir };
+
+use crate::convert::{ ConversionOptions, Converter, Input, Output };
+use crate::format::RowFormatter;
+use crate::plan::{ HeaderPlan, TagSelection };
+use crate::report::ConversionReport;
+use crate::sink::RowSink;
+use crate::writer::{ Layout, Writer };
+
+/// The header lines shared by the test VCFs, up to the column line.
+pub(crate) const HEADER: &str = "\
+##fileformat=VCFv4.2
+##contig=<ID=1,length=1000>
+##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Total depth\">
+##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency\">
+##INFO=<ID=RC,Number=R,Type=Integer,Description=\"Read counts\">
+##INFO=<ID=DB,Number=0,Type=Flag,Description=\"dbSNP membership\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+##FORMAT=<ID=AD,Number=R,Type=Integer,Description=\"Allele depths\">
+##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"Read depth\">
+##FORMAT=<ID=PL,Number=G,Type=Integer,Description=\"Phred-scaled likelihoods\">
+";
+
+/// More header lines, declaring tags with several values per row.
+const MULTI_VALUED_HEADER: &str = "\
+##INFO=<ID=MQ,Number=2,Type=Integer,Description=\"Mapping qualities\">
+##INFO=<ID=XS,Number=.,Type=String,Description=\"Names\">
+##FORMAT=<ID=HQ,Number=2,Type=Float,Description=\"Haplotype qualities\">
+##FORMAT=<ID=FT,Number=.,Type=String,Description=\"Filters\">
+";
+
+/// A VCF of `HEADER`, samples `s1` and `s2`, and the tab-separated `records`.
+pub(crate) fn vcf(records: &[&str]) -> String {
+    let mut text = HEADER.to_owned();
+    text.push_str("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2\n");
+    for record in records {
+        text.push_str(record);
+        text.push('\n');
+    }
+    text
+}
+
+/// A VCF as `vcf`, with the tags of `MULTI_VALUED_HEADER` declared too.
+pub(crate) fn multi_valued_vcf(records: &[&str]) -> String {
+    vcf(records).replacen("#CHROM", &format!("{}#CHROM", MULTI_VALUED_HEADER), 1)
+}
+
+/// The VCF `text` in a temporary file.
+pub(crate) fn vcf_file(text: &str) -> NamedTempFile {
+    let mut file = tempfile::Builder::new().suffix(".vcf").tempfile().unwrap();
+    file.write_all(text.as_bytes()).unwrap();
+    file
+}
+
+/// The VCF `text` bgzipped, with a CSI index, in a temporary directory.
+pub(crate) fn indexed_vcf_file(text: &str) -> (TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("calls.vcf.gz");
+    let mut writer = bgzf::Writer::from_path(&path).unwrap();
+    writer.write_all(text.as_bytes()).unwrap();
+    drop(writer);
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    // SAFETY: the path is a valid C string, which htslib only reads
+    assert_eq!(unsafe { htslib::bcf_index_build(c_path.as_ptr(), 14) }, 0);
+    (dir, path)
+}
+
+/// The header and records of the VCF `text`, read by htslib through a temporary file.
+pub(crate) fn read(text: &str) -> (HeaderView, Vec<bcf::Record>) {
+    let file = vcf_file(text);
+    let mut reader = bcf::Reader::from_path(file.path()).unwrap();
+    let header = reader.header().clone();
+    let records = reader.records().collect::<Result<_, _>>().unwrap();
+    (header, records)
+}
+
+/// The plan of the VCF `text` for all its tags.
+pub(crate) fn plan(text: &str) -> HeaderPlan {
+    let (header, _) = read(text);
+    HeaderPlan::new(&header, &TagSelection::All, &TagSelection::All).unwrap()
+}
+
+/// The sink `sink` makes for the plan `plan` makes of the header of the VCF `text`, once the
+/// VCF is converted to it; take the output from it with `into_inner`.
+pub(crate) fn written<S: RowSink>(
+    text: &str,
+    plan: impl FnOnce(&HeaderView) -> HeaderPlan,
+    sink: impl FnOnce(&HeaderPlan) -> S,
+) -> S {
+    let (header, _) = read(text);
+    let plan = plan(&header);
+    let mut sink = sink(&plan);
+    convert(text, &plan, &mut sink);
+    sink
+}
+
+/// Write the header and rows of the VCF `text` to `sink` following `plan`.
+pub(crate) fn convert<S: RowSink + ?Sized>(text: &str, plan: &HeaderPlan, sink: &mut S) {
+    let (header, records) = read(text);
+    sink.begin(&plan.columns()).unwrap();
+    let mut formatter = RowFormatter::new(plan);
+    for rec in &records {
+        formatter.write_record(&header, rec, sink).unwrap();
+    }
+    sink.finish().unwrap();
+}
+
+/// The TSV of the VCF `text` following `plan`, with its header laid out as `layout`.
+pub(crate) fn tsv(text: &str, plan: &HeaderPlan, layout: Layout) -> String {
+    let mut output = Vec::new();
+    convert(text, plan, &mut Writer::new(&mut output, layout));
+    String::from_utf8(output).unwrap()
+}
+
+/// The output and report of converting `input` with `options` into a file.
+pub(crate) fn run(
+    input: Input,
+    options: ConversionOptions,
+) -> anyhow::Result<(String, ConversionReport)> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("calls.tsv");
+    let report = Converter::new(input, Output::path(&path)).with_options(options).run()?;
+    Ok((fs::read_to_string(path).unwrap(), report))
+}
+
+/// A byte sink for `Output::writer` whose bytes the test keeps access to.
+#[derive(Clone, Default)]
+pub(crate) struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
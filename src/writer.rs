@@ -0,0 +1,248 @@
+use derive_new::new;
+use rust_htslib::bcf::record::Numeric;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::plan::{ Column, ColumnKind };
+use crate::sink::RowSink;
+use crate::ParseError;
+
+const HEADER_COMMON: &str = "VARIANT";
+
+/// How the column header of the output table is laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Two header rows: `VARIANT` or the sample name above each column name.
+    #[default]
+    Grouped,
+    /// A single header row, FORMAT columns being named `<sample>_<TAG>`.
+    Flat,
+}
+
+impl FromStr for Layout {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grouped" => Ok(Layout::Grouped),
+            "flat" => Ok(Layout::Flat),
+            _ => Err(ParseError::UnknownOption { option: "layout", value: s.to_owned() }),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Layout::Grouped => "grouped",
+            Layout::Flat => "flat",
+        })
+    }
+}
+
+impl Layout {
+    /// The header lines of a table with `columns`, as written by `Writer`.
+    pub fn header_rows(self, columns: &[Column]) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        if self == Layout::Grouped {
+            let groups = columns.iter().map(|column| {
+                match &column.kind {
+                    ColumnKind::Format { sample } => sample.clone(),
+                    _ => HEADER_COMMON.to_owned(),
+                }
+            });
+            rows.push(groups.collect());
+        }
+
+        let names = columns.iter().map(|column| {
+            match (&column.kind, self) {
+                (ColumnKind::Format { sample }, Layout::Flat) => {
+                    format!("{}_{}", sample, column.name)
+                }
+                _ => column.name.clone(),
+            }
+        });
+        rows.push(names.collect());
+        rows
+    }
+}
+
+/// Tab-delimited text writer, keeping track of the fields already written on the current line.
+#[derive(new)]
+pub struct Writer<W: Write> {
+    inner: W,
+    layout: Layout,
+    #[new(value = "0")]
+    field_count: usize,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a new field, writing the delimiter if it is not the first one on the line.
+    fn delimit(&mut self) -> anyhow::Result<()> {
+        if self.field_count > 0 {
+            self.inner.write_all(b"\t")?;
+        }
+        self.field_count += 1;
+        Ok(())
+    }
+
+    pub fn write_field(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        self.delimit()?;
+        self.inner.write_all(value)?;
+        Ok(())
+    }
+
+    pub fn newline(&mut self) -> anyhow::Result<()> {
+        self.inner.write_all(b"\n")?;
+        self.field_count = 0;
+        Ok(())
+    }
+
+    /// Append complete lines formatted by another `Writer`.
+    pub fn append(&mut self, lines: &[u8]) -> anyhow::Result<()> {
+        self.inner.write_all(lines)?;
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> RowSink for Writer<W> {
+    /// Written as `##key=value` comment lines, like in the VCF header.
+    fn metadata(&mut self, entries: &[(String, String)]) -> anyhow::Result<()> {
+        for (key, value) in entries {
+            writeln!(self.inner, "##{}={}", key, value)?;
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        for row in self.layout.header_rows(columns) {
+            for name in row {
+                self.write_field(name.as_bytes())?;
+            }
+            self.newline()?;
+        }
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        if value.is_missing() {
+            return self.write_field(b"");
+        }
+        self.delimit()?;
+        write!(self.inner, "{}", value)?;
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        if value.is_missing() {
+            return self.write_field(b"");
+        }
+        self.delimit()?;
+        write!(self.inner, "{}", value)?;
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        self.write_field(value)
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        self.write_field(b"")
+    }
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()> {
+        self.write_field(if value { b"true" } else { b"false" })
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        self.newline()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bcf::header::TagType;
+
+    fn columns() -> Vec<Column> {
+        let column = |kind, name: &str| Column {
+            kind,
+            name: name.to_owned(),
+            value_type: TagType::Integer,
+        };
+        vec![
+            column(ColumnKind::Fixed, "POS"),
+            column(ColumnKind::Info, "DP"),
+            column(ColumnKind::Format { sample: "s1".to_owned() }, "DP"),
+        ]
+    }
+
+    fn written(layout: Layout, write: impl FnOnce(&mut Writer<&mut Vec<u8>>)) -> String {
+        let mut output = Vec::new();
+        write(&mut Writer::new(&mut output, layout));
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn groups_columns_under_their_sample() {
+        let text = written(Layout::Grouped, |writer| writer.begin(&columns()).unwrap());
+        assert_eq!(text, "VARIANT\tVARIANT\ts1\nPOS\tDP\tDP\n");
+    }
+
+    #[test]
+    fn prefixes_format_columns_with_their_sample() {
+        let text = written(Layout::Flat, |writer| writer.begin(&columns()).unwrap());
+        assert_eq!(text, "POS\tDP\ts1_DP\n");
+    }
+
+    #[test]
+    fn writes_missing_values_as_empty_fields() {
+        let text = written(Layout::Flat, |writer| {
+            writer.write_int(i32::missing()).unwrap();
+            writer.write_float(f32::missing()).unwrap();
+            writer.write_null().unwrap();
+            writer.write_int(-3).unwrap();
+            writer.write_float(0.5).unwrap();
+            writer.write_flag(true).unwrap();
+            writer.write_flag(false).unwrap();
+            writer.end_row().unwrap();
+        });
+        assert_eq!(text, "\t\t\t-3\t0.5\ttrue\tfalse\n");
+    }
+
+    #[test]
+    fn writes_the_first_of_several_values() {
+        let text = written(Layout::Flat, |writer| {
+            writer.write_ints(&[4, 5]).unwrap();
+            writer.write_strs(&mut [&b"a"[..], b"b"].into_iter()).unwrap();
+            writer.end_row().unwrap();
+        });
+        assert_eq!(text, "4\ta\n");
+    }
+
+    #[test]
+    fn writes_metadata_as_comment_lines() {
+        let text = written(Layout::Flat, |writer| {
+            writer.metadata(&[("source".to_owned(), "test".to_owned())]).unwrap();
+        });
+        assert_eq!(text, "##source=test\n");
+    }
+}
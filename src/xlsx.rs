@@ -0,0 +1,323 @@
+use itertools::Itertools;
+use rust_xlsxwriter::{ ColNum, Format, FormatAlign, RowNum, Workbook, Worksheet };
+use std::io::Write;
+
+use crate::plan::Column;
+use crate::sink::RowSink;
+use crate::writer::Layout;
+
+/// Rows and columns of an Excel worksheet.
+const MAX_ROWS: RowNum = 1_048_576;
+const MAX_COLUMNS: usize = 16_384;
+/// UTF-16 code units of an Excel cell, the length Excel counts; longer strings are cut short.
+const MAX_STRING: usize = 32_767;
+/// Characters of a worksheet name.
+const MAX_SHEET_NAME: usize = 31;
+
+/// Writes rows into an Excel workbook, with typed cells and the header rows of `layout`
+/// frozen above an auto-filter.
+///
+/// In the grouped layout, the `VARIANT` and sample names are merged across their columns.
+/// Rows go on to a new worksheet when one is full, or, with `sheet_per_contig`, whenever the
+/// contig changes. Header metadata, if any, goes to a last `metadata` worksheet.
+///
+/// Worksheets are kept in constant memory, each row going to a temporary file once the next
+/// one starts, and the workbook is written to `inner` once complete.
+pub struct XlsxWriter<W: Write> {
+    inner: W,
+    layout: Layout,
+    sheet_per_contig: bool,
+    workbook: Workbook,
+    header_rows: Vec<Vec<String>>,
+    header_format: Format,
+    group_format: Format,
+    /// Index of the current worksheet in the workbook.
+    sheet: Option<usize>,
+    sheet_names: Vec<String>,
+    metadata: Vec<(String, String)>,
+    /// Contig of the current worksheet, with `sheet_per_contig`.
+    contig: Vec<u8>,
+    /// Worksheets the current contig, or the whole table, takes.
+    parts: usize,
+    row: RowNum,
+    column: ColNum,
+}
+
+impl<W: Write> XlsxWriter<W> {
+    pub fn new(inner: W, layout: Layout, sheet_per_contig: bool) -> Self {
+        XlsxWriter {
+            inner,
+            layout,
+            sheet_per_contig,
+            workbook: Workbook::new(),
+            header_rows: Vec::new(),
+            header_format: Format::new().set_bold(),
+            group_format: Format::new().set_bold().set_align(FormatAlign::Center),
+            sheet: None,
+            sheet_names: Vec::new(),
+            metadata: Vec::new(),
+            contig: Vec::new(),
+            parts: 0,
+            row: 0,
+            column: 0,
+        }
+    }
+
+    /// The cell of the next value, moving on to a new worksheet at the start of a row if needed.
+    /// `value` is only looked at for the first column, which holds the contig.
+    fn cell(
+        &mut self,
+        value: Option<&[u8]>,
+    ) -> anyhow::Result<(&mut Worksheet, RowNum, ColNum)> {
+        if self.column == 0 {
+            let new_contig = self.sheet_per_contig && value.is_some_and(|c| c != self.contig);
+            if new_contig {
+                self.contig = value.unwrap_or_default().to_vec();
+                self.parts = 0;
+            }
+            if self.sheet.is_none() || new_contig || self.row == MAX_ROWS {
+                self.start_sheet()?;
+            }
+        }
+        let column = self.column;
+        self.column += 1;
+        let index = self.sheet.expect("a worksheet is started before the first cell");
+        Ok((self.workbook.worksheet_from_index(index)?, self.row, column))
+    }
+
+    fn start_sheet(&mut self) -> anyhow::Result<()> {
+        self.finish_sheet()?;
+        self.parts += 1;
+        let base = if self.sheet_per_contig {
+            String::from_utf8_lossy(&self.contig).into_owned()
+        } else {
+            "variants".to_owned()
+        };
+        let name = sheet_name(&base, self.parts, &self.sheet_names);
+
+        let index = self.workbook.worksheets().len();
+        let sheet = self.workbook.add_worksheet_with_constant_memory();
+        sheet.set_name(&name)?;
+        for (row, names) in self.header_rows.iter().enumerate() {
+            let (row, format) = (row as RowNum, &self.header_format);
+            if row as usize + 1 == self.header_rows.len() {
+                for (column, name) in names.iter().enumerate() {
+                    sheet.write_string_with_format(row, column as ColNum, name, format)?;
+                }
+                continue;
+            }
+            // Group names are merged across the columns they head
+            let (mut first, format) = (0, &self.group_format);
+            for (group, columns) in &names.iter().group_by(|name| *name) {
+                let last = first + columns.count() - 1;
+                if first == last {
+                    sheet.write_string_with_format(row, first as ColNum, group, format)?;
+                } else {
+                    sheet.merge_range(row, first as ColNum, row, last as ColNum, group, format)?;
+                }
+                first = last + 1;
+            }
+        }
+        let header_lines = self.header_rows.len() as RowNum;
+        sheet.set_freeze_panes(header_lines, 0)?;
+
+        self.sheet = Some(index);
+        self.sheet_names.push(name);
+        self.row = header_lines;
+        Ok(())
+    }
+
+    /// Filter on the last header row of the current worksheet.
+    fn finish_sheet(&mut self) -> anyhow::Result<()> {
+        if let Some(index) = self.sheet.take() {
+            let sheet = self.workbook.worksheet_from_index(index)?;
+            let header_lines = self.header_rows.len() as RowNum;
+            let columns = self.header_rows.last().map_or(0, Vec::len) as ColNum;
+            if header_lines > 0 && columns > 0 {
+                let last_row = self.row.max(header_lines) - 1;
+                sheet.autofilter(header_lines - 1, 0, last_row, columns - 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> RowSink for XlsxWriter<W> {
+    fn metadata(&mut self, entries: &[(String, String)]) -> anyhow::Result<()> {
+        self.metadata = entries.to_vec();
+        Ok(())
+    }
+
+    fn begin(&mut self, columns: &[Column]) -> anyhow::Result<()> {
+        if columns.len() > MAX_COLUMNS {
+            anyhow::bail!(
+                "{} columns do not fit in an Excel worksheet; select fewer FORMAT tags",
+                columns.len()
+            );
+        }
+        self.header_rows = self.layout.header_rows(columns);
+        Ok(())
+    }
+
+    fn begin_row(&mut self) -> anyhow::Result<()> {
+        self.column = 0;
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i32) -> anyhow::Result<()> {
+        let (sheet, row, column) = self.cell(None)?;
+        sheet.write_number(row, column, value)?;
+        Ok(())
+    }
+
+    fn write_float(&mut self, value: f32) -> anyhow::Result<()> {
+        let (sheet, row, column) = self.cell(None)?;
+        // Through the shortest decimal form, so that 0.1 shows as 0.1
+        sheet.write_number(row, column, value.to_string().parse::<f64>()?)?;
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        let (sheet, row, column) = self.cell(Some(value))?;
+        let value = String::from_utf8_lossy(value);
+        sheet.write_string(row, column, truncate(&value))?;
+        Ok(())
+    }
+
+    fn write_null(&mut self) -> anyhow::Result<()> {
+        self.cell(None)?;
+        Ok(())
+    }
+
+    fn write_flag(&mut self, value: bool) -> anyhow::Result<()> {
+        let (sheet, row, column) = self.cell(None)?;
+        sheet.write_boolean(row, column, value)?;
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> anyhow::Result<()> {
+        self.row += 1;
+        self.column = 0;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if self.sheet_names.is_empty() {
+            self.start_sheet()?;
+        }
+        self.finish_sheet()?;
+        if !self.metadata.is_empty() {
+            let sheet = self.workbook.add_worksheet();
+            sheet.set_name(sheet_name("metadata", 1, &self.sheet_names))?;
+            for (row, (key, value)) in self.metadata.iter().enumerate() {
+                sheet.write_string(row as RowNum, 0, key)?;
+                sheet.write_string(row as RowNum, 1, value)?;
+            }
+        }
+        self.inner.write_all(&self.workbook.save_to_buffer()?)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// The longest start of `value` Excel keeps in a cell, not splitting a character.
+fn truncate(value: &str) -> &str {
+    if value.encode_utf16().count() <= MAX_STRING {
+        return value;
+    }
+    let mut units = 0;
+    for (i, c) in value.char_indices() {
+        units += c.len_utf16();
+        if units > MAX_STRING {
+            return &value[..i];
+        }
+    }
+    value
+}
+
+/// A valid worksheet name for the `part`-th worksheet of `base`, unlike any of `taken`.
+fn sheet_name(base: &str, part: usize, taken: &[String]) -> String {
+    let base: String = base
+        .chars()
+        .map(|c| if "[]:*?/\\".contains(c) { '_' } else { c })
+        .collect();
+    let base = base.trim_matches('\'');
+    let base = if base.is_empty() { "contig" } else { base };
+    (part..)
+        .map(|n| {
+            let suffix = if n > 1 { format!(" ({})", n) } else { String::new() };
+            let keep = MAX_SHEET_NAME - suffix.chars().count();
+            base.chars().take(keep).chain(suffix.chars()).collect::<String>()
+        })
+        .find(|name| !taken.iter().any(|t| t.eq_ignore_ascii_case(name)))
+        .expect("unbounded names")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::ColumnKind;
+    use rust_htslib::bcf::header::TagType;
+
+    fn columns() -> Vec<Column> {
+        let column = |name: &str, value_type| Column {
+            kind: ColumnKind::Fixed,
+            name: name.to_owned(),
+            value_type,
+        };
+        vec![column("CHROM", TagType::String), column("POS", TagType::Integer)]
+    }
+
+    fn write_row(writer: &mut XlsxWriter<Vec<u8>>, contig: &str, pos: i32) {
+        writer.begin_row().unwrap();
+        writer.write_str(contig.as_bytes()).unwrap();
+        writer.write_int(pos).unwrap();
+        writer.end_row().unwrap();
+    }
+
+    /// The worksheets `rows` of `(contig, position)` take.
+    fn sheets(sheet_per_contig: bool, rows: &[(&str, i32)]) -> Vec<String> {
+        let mut writer = XlsxWriter::new(Vec::new(), Layout::Flat, sheet_per_contig);
+        writer.begin(&columns()).unwrap();
+        for (contig, pos) in rows {
+            write_row(&mut writer, contig, *pos);
+        }
+        writer.finish().unwrap();
+        assert!(writer.inner.starts_with(b"PK"));
+        writer.sheet_names
+    }
+
+    #[test]
+    fn starts_a_new_worksheet_when_one_is_full() {
+        let mut writer = XlsxWriter::new(Vec::new(), Layout::Flat, false);
+        writer.begin(&columns()).unwrap();
+        write_row(&mut writer, "1", 1);
+        // Skip to the last row of the worksheet rather than fill it
+        writer.row = MAX_ROWS - 1;
+        write_row(&mut writer, "1", 2);
+        assert_eq!(writer.sheet_names, ["variants"]);
+        write_row(&mut writer, "1", 3);
+        assert_eq!(writer.sheet_names, ["variants", "variants (2)"]);
+        // Below the header line of the new worksheet
+        assert_eq!(writer.row, 2);
+        writer.finish().unwrap();
+        assert!(writer.inner.starts_with(b"PK"));
+    }
+
+    #[test]
+    fn starts_a_new_worksheet_per_contig() {
+        let rows = [("1", 1), ("1", 2), ("X:2", 1), ("x:2", 1)];
+        assert_eq!(sheets(true, &rows), ["1", "X_2", "x_2 (2)"]);
+        assert_eq!(sheets(false, &rows), ["variants"]);
+    }
+
+    #[test]
+    fn cuts_strings_at_the_excel_limit_in_utf16_units() {
+        let short = "a".repeat(MAX_STRING);
+        assert_eq!(truncate(&short), short);
+        // Each of these characters takes two UTF-16 code units
+        let long = "\u{1F600}".repeat(MAX_STRING / 2 + 1);
+        assert_eq!(truncate(&long).encode_utf16().count(), MAX_STRING - 1);
+        assert_eq!(truncate(&long).chars().count(), MAX_STRING / 2);
+    }
+}
@@ -0,0 +1,35 @@
+//! Runs the `vcf2csv` binary on small VCFs written inline.
+
+use std::io::Write;
+use std::process::{ Command, Stdio };
+
+const VCF: &str = "\
+##fileformat=VCFv4.2
+##contig=<ID=1,length=1000>
+##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Total depth\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1
+1\t100\t.\tA\tC,G\t50\tPASS\tDP=10\tGT\t1|2
+1\t200\t.\tT\tG\t.\t.\t.\tGT\t0/1
+";
+
+#[test]
+fn converts_stdin_to_stdout() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_vcf2csv"))
+        .args(["--layout", "flat", "-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(VCF.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "CHROM\tPOS\tREF\tALT\tQUAL\tFILTER\tDP\ts1_GT\n\
+         1\t100\tA\tC\t50\tPASS\t10\t1|.\n\
+         1\t100\tA\tG\t50\tPASS\t10\t.|1\n\
+         1\t200\tT\tG\t\t\t\t0/1\n"
+    );
+}